@@ -0,0 +1,555 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use clap::ValueEnum;
+
+use crate::cargo_toml::{self, VersionConflict};
+use crate::digest;
+use crate::journal::{self, Change, ChangeKind, Journal};
+use crate::lockfile::{self, LockedPreset, Lockfile};
+use crate::store::Store;
+use crate::template::{self, RenderedFile};
+use crate::variables::{self, Variables};
+
+/// What to do with a file that already exists with different contents.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnConflict {
+    /// Leave the existing file alone
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Rename the existing file to `<name>.bak` and write the new one
+    Backup,
+    /// Abort before writing anything
+    #[default]
+    Fail,
+    /// Ask for every conflicting file
+    Prompt,
+}
+
+/// State of a destination path before the preset is applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// The file doesn't exist yet
+    Create,
+    /// The file exists and is replaced on purpose, e.g. a merged Cargo.toml
+    Overwrite,
+    /// The file exists with the same contents
+    Identical,
+    /// The path exists with different contents
+    Conflict,
+    /// Local changes and preset changes were merged cleanly
+    Merged,
+    /// Local changes and preset changes were merged with conflict markers
+    Conflicted,
+    /// The file is no longer part of the preset and is deleted
+    Remove,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Status::Create => "create",
+            Status::Overwrite => "overwrite",
+            Status::Identical => "identical",
+            Status::Conflict => "conflict",
+            Status::Merged => "merged",
+            Status::Conflicted => "conflicted",
+            Status::Remove => "remove",
+        })
+    }
+}
+
+/// How a planned file ends up being written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Write,
+    Backup,
+    Skip,
+    Remove,
+}
+
+pub struct Planned {
+    pub path: PathBuf,
+    pub contents: Vec<u8>,
+    /// What the preset itself renders, when `contents` also has local changes merged in.
+    pub rendered: Option<Vec<u8>>,
+    /// Preset the file comes from, or `None` for files cargo-preset updates
+    /// itself, like a merged Cargo.toml.
+    pub preset: Option<String>,
+    pub status: Status,
+    pub action: Action,
+}
+
+impl Planned {
+    pub fn new(file: RenderedFile, preset: Option<&str>, status: Status) -> Self {
+        let action = match status {
+            Status::Identical => Action::Skip,
+            Status::Remove => Action::Remove,
+            _ => Action::Write,
+        };
+        Self {
+            path: file.path,
+            contents: file.contents,
+            rendered: None,
+            preset: preset.map(str::to_owned),
+            status,
+            action,
+        }
+    }
+
+    /// Contents as rendered by the preset, without local changes.
+    pub fn preset_contents(&self) -> &[u8] {
+        self.rendered.as_deref().unwrap_or(&self.contents)
+    }
+}
+
+/// Every file a preset would write into a directory, worked out before touching it.
+pub struct Plan {
+    pub dir: PathBuf,
+    /// Presets being applied, with their files filled in by [`Plan::lock`].
+    pub presets: Vec<LockedPreset>,
+    pub files: Vec<Planned>,
+}
+
+/// Plan applying the presets `names` to `dir` in order, including changes to
+/// its Cargo.toml. When presets have a file in common, the last one's is used.
+///
+/// `defined` variables take precedence over the builtin ones, and values of
+/// declared variables that are missing are asked for if `interactive`. These
+/// values are added to `defined`, so that later presets, and later calls, use
+/// them too.
+pub fn plan_presets(
+    store: &Store,
+    names: &[String],
+    dir: &Path,
+    defined: &mut Variables,
+    interactive: bool,
+    version_conflict: VersionConflict,
+) -> anyhow::Result<Plan> {
+    let mut plan = Plan::new(dir.to_owned());
+    for (i, name) in names.iter().enumerate() {
+        if names[..i].contains(name) {
+            anyhow::bail!(format!("Preset {name} is listed more than once"));
+        }
+
+        let mut vars = variables::builtin(dir)?;
+        vars.extend(defined.clone());
+        let rendered = render_preset(store, name, dir, vars, interactive, version_conflict, plan.cargo_manifest())?;
+        for file in rendered.files {
+            if let Some(other) = plan.files.iter().find(|planned| planned.path == file.path) {
+                let other = other.preset.as_deref().unwrap_or_default();
+                println!("{} is in both {other} and {name}, using {name}'s", file.path.display());
+            }
+            plan.add(file, Some(name))?;
+        }
+        if let Some(cargo_manifest) = rendered.cargo_manifest {
+            plan.add(cargo_manifest, None)?;
+        }
+        defined.extend(rendered.answered);
+        plan.presets.push(rendered.locked);
+    }
+
+    Ok(plan)
+}
+
+/// A preset rendered for a directory.
+pub struct Rendered {
+    /// The preset and the variables it was rendered with, without files.
+    pub locked: LockedPreset,
+    pub files: Vec<RenderedFile>,
+    /// Values of declared variables that were missing from the given ones.
+    pub answered: Variables,
+    /// The directory's Cargo.toml with the preset's fragment merged in.
+    pub cargo_manifest: Option<RenderedFile>,
+}
+
+/// Render the preset `name` for `dir` with `vars`, plus values for the
+/// variables it declares, asking for them if `interactive`.
+///
+/// Cargo.toml fragments are merged into `cargo_manifest`, or the directory's
+/// Cargo.toml if that's `None`.
+pub fn render_preset(
+    store: &Store,
+    name: &str,
+    dir: &Path,
+    mut vars: Variables,
+    interactive: bool,
+    version_conflict: VersionConflict,
+    cargo_manifest: Option<String>,
+) -> anyhow::Result<Rendered> {
+    let layers = store.layers(name)?;
+    let manifests = layers.iter()
+        .map(|layer| store.manifest(layer))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // A preset's declarations take precedence over the ones of presets it extends.
+    let mut specs = BTreeMap::new();
+    for manifest in &manifests {
+        specs.extend(manifest.variables.clone());
+    }
+    let given = vars.clone();
+    variables::resolve(&mut vars, &specs, interactive)
+        .with_context(|| format!("Could not apply preset {name}"))?;
+    let answered = vars.iter()
+        .filter(|(var, _)| !given.contains_key(*var))
+        .map(|(var, value)| (var.clone(), value.clone()))
+        .collect();
+
+    let mut files = BTreeMap::new();
+    let mut fragments = Vec::new();
+    for layer in &layers {
+        for file in template::render_dir(&store.path(layer), &vars)? {
+            files.insert(file.path.clone(), file);
+        }
+        let fragment = store.path(layer).join(cargo_toml::FRAGMENT_FILE_NAME);
+        if fragment.exists() {
+            fragments.push((layer, fragment));
+        }
+    }
+
+    let cargo_manifest = if fragments.is_empty() {
+        None
+    } else {
+        let path = dir.join("Cargo.toml");
+        let mut contents = match cargo_manifest {
+            Some(contents) => contents,
+            None if path.exists() => fs::read_to_string(&path)?,
+            None => anyhow::bail!(format!("Preset {name} extends Cargo.toml, but {} has none", dir.display())),
+        };
+        for (layer, fragment) in fragments {
+            let merged = cargo_toml::merge(&contents, &fs::read_to_string(&fragment)?, version_conflict)
+                .with_context(|| format!("Could not merge the Cargo.toml fragment of {layer}"))?;
+            for note in merged.notes {
+                println!("Cargo.toml: {note}");
+            }
+            contents = merged.contents;
+        }
+        Some(RenderedFile { path: "Cargo.toml".into(), contents: contents.into_bytes() })
+    };
+
+    let manifest = manifests.into_iter().next_back().expect("a preset is its own last layer");
+    let files = files.into_values().collect();
+    let locked = LockedPreset {
+        name: name.to_owned(),
+        version: manifest.version,
+        hash: store.content_hash(name)?,
+        variables: vars,
+        files: Default::default(),
+    };
+    Ok(Rendered { locked, files, answered, cargo_manifest })
+}
+
+impl Plan {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir, presets: Vec::new(), files: Vec::new() }
+    }
+
+    /// Add a file coming from `preset`. Files without a preset are meant to
+    /// replace an existing one rather than conflict with it.
+    pub fn add(&mut self, file: RenderedFile, preset: Option<&str>) -> anyhow::Result<()> {
+        let dest = self.dir.join(&file.path);
+        let status = if blocked(&self.dir, &file.path) {
+            Status::Conflict
+        } else if !dest.exists() {
+            Status::Create
+        } else if fs::read(&dest)? == file.contents {
+            Status::Identical
+        } else if preset.is_none() {
+            Status::Overwrite
+        } else {
+            Status::Conflict
+        };
+
+        self.push(Planned::new(file, preset, status));
+        Ok(())
+    }
+
+    /// Cargo.toml as already changed by the plan, if it is.
+    pub fn cargo_manifest(&self) -> Option<String> {
+        self.files.iter()
+            .find(|file| file.path == Path::new("Cargo.toml") && file.preset.is_none())
+            .map(|file| String::from_utf8_lossy(&file.contents).into_owned())
+    }
+
+    pub fn push(&mut self, file: Planned) {
+        self.files.retain(|planned| planned.path != file.path);
+        self.files.push(file);
+    }
+
+    /// Add the project's lockfile to the plan, updated with the presets being
+    /// applied and the files they end up writing, along with snapshots of those
+    /// files to merge against when the presets are updated.
+    pub fn lock(&mut self) -> anyhow::Result<()> {
+        let mut lockfile = Lockfile::load(&self.dir)?;
+        let mut snapshots = Vec::new();
+        for preset in &self.presets {
+            let mut locked = preset.clone();
+            for file in &self.files {
+                let kept = file.status == Status::Identical
+                    || !matches!(file.action, Action::Skip | Action::Remove);
+                if file.preset.as_ref() != Some(&preset.name) || !kept {
+                    continue;
+                }
+
+                let contents = file.preset_contents();
+                locked.files.insert(file.path.to_string_lossy().into_owned(), digest::sha256(contents));
+                snapshots.push(RenderedFile {
+                    path: lockfile::base_path(&preset.name, &file.path),
+                    contents: contents.to_owned(),
+                });
+            }
+            lockfile.insert(locked);
+        }
+
+        for snapshot in snapshots {
+            self.add(snapshot, None)?;
+        }
+        let contents = lockfile.to_string()?.into_bytes();
+        self.add(RenderedFile { path: lockfile::FILE_NAME.into(), contents }, None)
+    }
+
+    /// List what would happen to every file under `policy`.
+    pub fn print(&self, policy: OnConflict) {
+        for file in &self.files {
+            if file.path.starts_with(journal::STATE_DIR) {
+                continue;
+            }
+            if file.status == Status::Conflict {
+                let resolution = match policy {
+                    _ if blocked(&self.dir, &file.path) => "not a file",
+                    OnConflict::Skip => "skip",
+                    OnConflict::Overwrite => "overwrite",
+                    OnConflict::Backup => "backup",
+                    OnConflict::Fail => "fail",
+                    OnConflict::Prompt => "prompt",
+                };
+                println!("{:<10} {} ({resolution})", file.status, file.path.display());
+            } else {
+                println!("{:<10} {}", file.status, file.path.display());
+            }
+        }
+    }
+
+    /// Decide what to do with every conflicting file, failing if any can't be written.
+    pub fn resolve(&mut self, policy: OnConflict) -> anyhow::Result<()> {
+        let mut unresolved = Vec::new();
+        for file in self.files.iter_mut().filter(|file| file.status == Status::Conflict) {
+            let is_blocked = blocked(&self.dir, &file.path);
+            file.action = match policy {
+                OnConflict::Skip => Action::Skip,
+                _ if is_blocked => {
+                    unresolved.push(format!("{} (not a file)", file.path.display()));
+                    continue;
+                }
+                OnConflict::Overwrite => Action::Write,
+                OnConflict::Backup => Action::Backup,
+                OnConflict::Fail => {
+                    unresolved.push(file.path.display().to_string());
+                    continue;
+                }
+                OnConflict::Prompt => prompt(&file.path)?,
+            };
+        }
+
+        if !unresolved.is_empty() {
+            anyhow::bail!(
+                "Conflicting files, nothing was written (see --on-conflict):\n\t{}",
+                unresolved.join("\n\t")
+            );
+        }
+        Ok(())
+    }
+
+    /// Write every resolved file as a single step and record it in the
+    /// project's journal, returning the journal entry's id.
+    ///
+    /// All files are first staged in a temporary directory inside the target,
+    /// then moved into place. If anything fails while moving, files that were
+    /// already written are removed and the ones they replaced are restored.
+    pub fn write(&self) -> anyhow::Result<u64> {
+        let state = self.dir.join(journal::STATE_DIR);
+        fs::create_dir_all(&state)?;
+        let staging = tempfile::Builder::new()
+            .prefix("staging-")
+            .tempdir_in(&state)
+            .context("Could not create staging directory")?;
+
+        let files: Vec<_> = self.files.iter()
+            .filter(|file| file.action != Action::Skip)
+            .collect();
+
+        for file in files.iter().filter(|file| file.action != Action::Remove) {
+            let staged = staging.path().join("files").join(&file.path);
+            fs::create_dir_all(staged.parent().expect("staged files have a parent"))?;
+            fs::write(&staged, &file.contents)
+                .with_context(|| format!("Could not stage {}", file.path.display()))?;
+        }
+
+        let mut transaction = Transaction::new(&self.dir, staging.path());
+        for file in &files {
+            if let Err(err) = transaction.commit(file) {
+                transaction.rollback();
+                return Err(err.context(format!("Could not write {}, no files were changed", file.path.display())));
+            }
+        }
+
+        let journal = Journal::open(&self.dir);
+        let changes = std::mem::take(&mut transaction.changes);
+        let created_dirs = transaction.created_dirs.iter()
+            .map(|dir| dir.strip_prefix(&self.dir).unwrap_or(dir).to_owned())
+            .collect();
+        let presets: Vec<_> = self.presets.iter().map(|preset| preset.name.clone()).collect();
+        match journal.record(&presets, changes, created_dirs, &staging.path().join("backup")) {
+            Ok(id) => Ok(id),
+            Err(err) => {
+                transaction.rollback();
+                Err(err.context("Could not record the apply in the journal, no files were changed"))
+            }
+        }
+    }
+}
+
+/// Changes made while moving staged files into place, so they can be undone.
+struct Transaction<'a> {
+    dir: &'a Path,
+    staging: &'a Path,
+    created_dirs: Vec<PathBuf>,
+    written: Vec<PathBuf>,
+    moved: Vec<(PathBuf, PathBuf)>,
+    changes: Vec<Change>,
+}
+
+impl<'a> Transaction<'a> {
+    fn new(dir: &'a Path, staging: &'a Path) -> Self {
+        Self {
+            dir,
+            staging,
+            created_dirs: Vec::new(),
+            written: Vec::new(),
+            moved: Vec::new(),
+            changes: Vec::new(),
+        }
+    }
+
+    fn commit(&mut self, file: &Planned) -> anyhow::Result<()> {
+        let dest = self.dir.join(&file.path);
+        let mut change = Change {
+            path: file.path.clone(),
+            kind: ChangeKind::Created,
+            hash: digest::sha256(&file.contents),
+            backup: None,
+        };
+
+        if dest.exists() {
+            let moved_to = match file.action {
+                Action::Backup => {
+                    let backup = backup_path(&dest);
+                    change.kind = ChangeKind::BackedUp;
+                    change.backup = Some(backup.strip_prefix(self.dir)?.to_owned());
+                    backup
+                }
+                _ => {
+                    let backup = self.staging.join("backup").join(&file.path);
+                    fs::create_dir_all(backup.parent().expect("backups have a parent"))?;
+                    change.kind = ChangeKind::Overwritten;
+                    backup
+                }
+            };
+            fs::rename(&dest, &moved_to)?;
+            self.moved.push((dest.clone(), moved_to));
+        }
+
+        if file.action == Action::Remove {
+            // Nothing is written, so remember what was removed instead.
+            let (_, removed) = self.moved.last().expect("removed files exist");
+            change.kind = ChangeKind::Removed;
+            change.hash = digest::sha256(&fs::read(removed)?);
+            self.changes.push(change);
+            return Ok(());
+        }
+
+        let parent = dest.parent().expect("destinations have a parent");
+        let missing: Vec<_> = parent.ancestors()
+            .take_while(|dir| !dir.exists())
+            .map(Path::to_owned)
+            .collect();
+        fs::create_dir_all(parent)?;
+        self.created_dirs.extend(missing.into_iter().rev());
+
+        fs::rename(self.staging.join("files").join(&file.path), &dest)?;
+        self.written.push(dest);
+        self.changes.push(change);
+        Ok(())
+    }
+
+    /// Undo everything committed so far, as far as possible.
+    fn rollback(self) {
+        for path in self.written.iter().rev() {
+            if let Err(err) = fs::remove_file(path) {
+                eprintln!("Could not remove {}: {err}", path.display());
+            }
+        }
+        for (original, moved_to) in self.moved.iter().rev() {
+            if let Err(err) = fs::rename(moved_to, original) {
+                eprintln!("Could not restore {} from {}: {err}", original.display(), moved_to.display());
+            }
+        }
+        for dir in self.created_dirs.iter().rev() {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
+
+/// Whether `rel` can't be written as a file in `dir` because it, or one of its
+/// parents, is in the way.
+fn blocked(dir: &Path, rel: &Path) -> bool {
+    if dir.join(rel).is_dir() {
+        return true;
+    }
+    rel.ancestors()
+        .skip(1)
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .any(|parent| {
+            let path = dir.join(parent);
+            path.exists() && !path.is_dir()
+        })
+}
+
+/// First of `<path>.bak`, `<path>.bak.1`, ... that doesn't exist.
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    let mut candidate = PathBuf::from(&backup);
+    let mut n = 1;
+    while candidate.exists() {
+        let mut numbered = backup.clone();
+        numbered.push(format!(".{n}"));
+        candidate = numbered.into();
+        n += 1;
+    }
+    candidate
+}
+
+fn prompt(path: &Path) -> anyhow::Result<Action> {
+    let stdin = io::stdin();
+    loop {
+        print!("{} already exists. [o]verwrite, [b]ackup, [s]kip or [a]bort? ", path.display());
+        io::stdout().flush()?;
+
+        let mut answer = String::new();
+        if stdin.lock().read_line(&mut answer)? == 0 {
+            anyhow::bail!("Aborted");
+        }
+        match answer.trim() {
+            "o" | "overwrite" => return Ok(Action::Write),
+            "b" | "backup" => return Ok(Action::Backup),
+            "s" | "skip" => return Ok(Action::Skip),
+            "a" | "abort" => anyhow::bail!("Aborted"),
+            _ => continue,
+        }
+    }
+}
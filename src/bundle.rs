@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Context;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use zip::write::SimpleFileOptions;
+
+use crate::digest;
+use crate::store::{PresetName, Store};
+use crate::template;
+
+/// File at the root of a bundle listing the hash of every other file in it,
+/// in the format of `sha256sum`.
+const CHECKSUMS_FILE_NAME: &str = "SHA256SUMS";
+
+#[derive(Clone, Copy)]
+enum Format {
+    TarGz,
+    Zip,
+}
+
+impl Format {
+    fn of(path: &Path) -> anyhow::Result<Self> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Ok(Self::TarGz)
+        } else if name.ends_with(".zip") {
+            Ok(Self::Zip)
+        } else {
+            anyhow::bail!(format!("{} should end in .tar.gz, .tgz or .zip", path.display()))
+        }
+    }
+}
+
+/// Files of a bundle, relative to its root. The first component of each is
+/// the preset it belongs to.
+pub type Files = BTreeMap<PathBuf, Vec<u8>>;
+
+/// Write the presets `names` to the bundle at `path`, its format picked from
+/// the extension.
+pub fn export(store: &Store, names: &[String], path: &Path) -> anyhow::Result<()> {
+    let format = Format::of(path)?;
+
+    let mut files = Files::new();
+    for name in names {
+        let root = store.path(name);
+        for file in template::walk(&root)? {
+            let rel = Path::new(name).join(file.strip_prefix(&root)?);
+            files.insert(rel, fs::read(&file)?);
+        }
+    }
+
+    let checksums: String = files.iter()
+        .map(|(path, contents)| format!("{}  {}\n", digest::sha256(contents), path.to_string_lossy()))
+        .collect();
+    files.insert(CHECKSUMS_FILE_NAME.into(), checksums.into_bytes());
+
+    let out = File::create(path)
+        .with_context(|| format!("Could not create {}", path.display()))?;
+    match format {
+        Format::TarGz => {
+            let mut builder = tar::Builder::new(GzEncoder::new(out, flate2::Compression::default()));
+            for (path, contents) in &files {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(contents.len() as u64);
+                header.set_mode(0o644);
+                builder.append_data(&mut header, path, contents.as_slice())?;
+            }
+            builder.into_inner()?.finish()?;
+        }
+        Format::Zip => {
+            let mut writer = zip::ZipWriter::new(out);
+            for (path, contents) in &files {
+                writer.start_file(path.to_string_lossy(), SimpleFileOptions::default())?;
+                writer.write_all(contents)?;
+            }
+            writer.finish()?;
+        }
+    }
+    Ok(())
+}
+
+/// Read the bundle at `path`, checking that every file is listed in its
+/// checksums with the right hash and stays inside the bundle.
+pub fn read(path: &Path) -> anyhow::Result<Files> {
+    let format = Format::of(path)?;
+    let file = File::open(path)
+        .with_context(|| format!("Could not open {}", path.display()))?;
+
+    let mut files = Files::new();
+    match format {
+        Format::TarGz => {
+            let mut archive = tar::Archive::new(GzDecoder::new(file));
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+                match entry.header().entry_type() {
+                    tar::EntryType::Directory => continue,
+                    tar::EntryType::Regular => {}
+                    _ => anyhow::bail!(format!("Bundle entry {name} is not a regular file")),
+                }
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents)?;
+                insert(&mut files, &name, contents)?;
+            }
+        }
+        Format::Zip => {
+            let mut archive = zip::ZipArchive::new(file)?;
+            for i in 0..archive.len() {
+                let mut entry = archive.by_index(i)?;
+                let name = entry.name().to_owned();
+                if entry.is_dir() {
+                    continue;
+                }
+                if !entry.is_file() {
+                    anyhow::bail!(format!("Bundle entry {name} is not a regular file"));
+                }
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents)?;
+                insert(&mut files, &name, contents)?;
+            }
+        }
+    }
+
+    verify(&mut files)?;
+    Ok(files)
+}
+
+fn insert(files: &mut Files, name: &str, contents: Vec<u8>) -> anyhow::Result<()> {
+    let path = Path::new(name);
+    // Anything but plain names could write outside of the store (zip-slip).
+    if !path.components().all(|component| matches!(component, Component::Normal(_)))
+        || name.contains('\\')
+    {
+        anyhow::bail!(format!("Bundle entry {name} points outside of the bundle"));
+    }
+    if files.insert(path.to_owned(), contents).is_some() {
+        anyhow::bail!(format!("Bundle has {name} more than once"));
+    }
+    Ok(())
+}
+
+/// Check the files against the checksums and drop the checksums file.
+fn verify(files: &mut Files) -> anyhow::Result<()> {
+    let checksums = files.remove(Path::new(CHECKSUMS_FILE_NAME))
+        .context("Bundle has no checksums")?;
+    let checksums = String::from_utf8(checksums).context("Invalid bundle checksums")?;
+
+    let mut listed = BTreeMap::new();
+    for line in checksums.lines().filter(|line| !line.is_empty()) {
+        let (hash, path) = line.split_once("  ")
+            .with_context(|| format!("Invalid bundle checksum line {line:?}"))?;
+        listed.insert(PathBuf::from(path), hash);
+    }
+
+    for (path, contents) in files.iter() {
+        if path.components().count() < 2 {
+            anyhow::bail!(format!("Bundle entry {} is not inside a preset", path.display()));
+        }
+        match listed.remove(path) {
+            Some(hash) if digest::sha256(contents) == hash => {}
+            Some(_) => anyhow::bail!(format!("Checksum of {} doesn't match, the bundle is corrupted", path.display())),
+            None => anyhow::bail!(format!("{} is missing from the bundle checksums", path.display())),
+        }
+    }
+    if let Some(path) = listed.keys().next() {
+        anyhow::bail!(format!("{} is listed in the bundle checksums but missing", path.display()));
+    }
+    Ok(())
+}
+
+/// Names of the presets in a bundle.
+pub fn presets(files: &Files) -> anyhow::Result<Vec<PresetName>> {
+    let mut names: Vec<_> = files.keys()
+        .filter_map(|path| path.components().next())
+        .map(|component| component.as_os_str().to_string_lossy().parse::<PresetName>())
+        .collect::<anyhow::Result<_>>()
+        .context("Invalid bundle")?;
+    names.dedup();
+    Ok(names)
+}
+
+/// Write the files of the preset `name` from a bundle to `dir`.
+pub fn extract(files: &Files, name: &str, dir: &Path) -> anyhow::Result<()> {
+    for (path, contents) in files {
+        let Ok(rel) = path.strip_prefix(name) else {
+            continue;
+        };
+        let dest = dir.join(rel);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&dest, contents)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle(entries: &[(&str, &str)]) -> Files {
+        let mut files = Files::new();
+        let mut checksums = String::new();
+        for (path, contents) in entries {
+            checksums.push_str(&format!("{}  {path}\n", digest::sha256(contents.as_bytes())));
+            files.insert(PathBuf::from(path), contents.as_bytes().to_vec());
+        }
+        files.insert(CHECKSUMS_FILE_NAME.into(), checksums.into_bytes());
+        files
+    }
+
+    #[test]
+    fn insert_rejects_paths_outside_of_the_bundle() {
+        for name in ["../evil", "a/../../evil", "/etc/passwd", "a\\..\\evil", "./a/b"] {
+            let mut files = Files::new();
+            assert!(insert(&mut files, name, Vec::new()).is_err(), "{name} was accepted");
+        }
+
+        let mut files = Files::new();
+        insert(&mut files, "preset/src/main.rs", Vec::new()).unwrap();
+        assert!(files.contains_key(Path::new("preset/src/main.rs")));
+    }
+
+    #[test]
+    fn insert_rejects_duplicate_entries() {
+        let mut files = Files::new();
+        insert(&mut files, "preset/a.txt", b"one".to_vec()).unwrap();
+        let err = insert(&mut files, "preset/a.txt", b"two".to_vec()).unwrap_err();
+        assert!(err.to_string().contains("more than once"), "{err}");
+    }
+
+    #[test]
+    fn verify_accepts_matching_checksums() {
+        let mut files = bundle(&[("preset/a.txt", "a"), ("preset/src/b.rs", "b")]);
+        verify(&mut files).unwrap();
+        assert!(!files.contains_key(Path::new(CHECKSUMS_FILE_NAME)));
+        assert_eq!(presets(&files).unwrap(), vec!["preset".parse::<PresetName>().unwrap()]);
+    }
+
+    #[test]
+    fn verify_rejects_bad_checksums() {
+        let mut files = bundle(&[("preset/a.txt", "a")]);
+        files.insert("preset/a.txt".into(), b"tampered".to_vec());
+        assert!(verify(&mut files).unwrap_err().to_string().contains("doesn't match"));
+
+        let mut files = bundle(&[("preset/a.txt", "a")]);
+        files.insert("preset/extra.txt".into(), b"extra".to_vec());
+        assert!(verify(&mut files).unwrap_err().to_string().contains("missing from the bundle checksums"));
+
+        let mut files = bundle(&[("preset/a.txt", "a")]);
+        files.remove(Path::new("preset/a.txt"));
+        assert!(verify(&mut files).unwrap_err().to_string().contains("listed in the bundle checksums"));
+
+        let mut files = bundle(&[("preset/a.txt", "a")]);
+        files.remove(Path::new(CHECKSUMS_FILE_NAME));
+        assert!(verify(&mut files).is_err());
+
+        let mut files = bundle(&[("loose.txt", "a")]);
+        assert!(verify(&mut files).unwrap_err().to_string().contains("not inside a preset"));
+    }
+
+    #[test]
+    fn read_rejects_zip_slip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("evil.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&path).unwrap());
+        writer.start_file("preset/../../evil.txt", SimpleFileOptions::default()).unwrap();
+        writer.write_all(b"evil").unwrap();
+        writer.finish().unwrap();
+
+        let err = read(&path).unwrap_err();
+        assert!(err.to_string().contains("points outside of the bundle"), "{err}");
+    }
+}
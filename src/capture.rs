@@ -0,0 +1,63 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+
+use crate::journal;
+use crate::lockfile;
+use crate::store;
+
+/// Per-directory ignore file, with the same syntax as `.gitignore`.
+pub const IGNORE_FILE_NAME: &str = ".presetignore";
+
+/// Never captured, wherever they appear in the project.
+const BUILTIN_EXCLUDES: &[&str] = &["target", ".git", journal::STATE_DIR, lockfile::FILE_NAME, store::PROJECT_DIR];
+
+/// Files of the project at `root` to capture as a preset, relative to it.
+///
+/// Honors `.gitignore` and `.presetignore` files. If `include` is non-empty,
+/// only files matching one of its globs are captured, and files matching any
+/// glob in `exclude` never are.
+pub fn project_files(root: &Path, include: &[String], exclude: &[String]) -> anyhow::Result<Vec<PathBuf>> {
+    let include = glob_set(include)?;
+    let exclude = glob_set(exclude)?;
+
+    let walker = WalkBuilder::new(root)
+        .hidden(false)
+        .require_git(false)
+        .git_global(false)
+        .add_custom_ignore_filename(IGNORE_FILE_NAME)
+        .filter_entry(|entry| {
+            !BUILTIN_EXCLUDES.iter().any(|name| entry.file_name() == *name)
+        })
+        .build();
+
+    let mut files = Vec::new();
+    for entry in walker {
+        let entry = entry?;
+        if !entry.file_type().is_some_and(|ty| ty.is_file()) {
+            continue;
+        }
+
+        let rel = entry.path().strip_prefix(root)?.to_owned();
+        if rel == Path::new(IGNORE_FILE_NAME) {
+            continue;
+        }
+        if (!include.is_empty() && !include.is_match(&rel)) || exclude.is_match(&rel) {
+            continue;
+        }
+        files.push(rel);
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+fn glob_set(patterns: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("Invalid glob {pattern:?}"))?);
+    }
+    Ok(builder.build()?)
+}
@@ -0,0 +1,339 @@
+use anyhow::Context;
+use clap::ValueEnum;
+use semver::VersionReq;
+use toml_edit::{DocumentMut, InlineTable, Item, TableLike, Value};
+
+/// Preset file merged into the project's Cargo.toml instead of being copied.
+pub const FRAGMENT_FILE_NAME: &str = "Cargo.fragment.toml";
+
+const DEPENDENCY_TABLES: &[&str] = &["dependencies", "dev-dependencies", "build-dependencies"];
+
+/// What to do when the project and the preset require different versions of a dependency.
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum VersionConflict {
+    /// Keep the project's version
+    #[default]
+    Keep,
+    /// Use whichever version is higher
+    Bump,
+    /// Abort the apply
+    Error,
+}
+
+/// Result of merging a fragment into a manifest.
+pub struct Merged {
+    pub contents: String,
+    /// Human readable notes about conflicting values and how they were resolved.
+    pub notes: Vec<String>,
+}
+
+/// Deep-merge `fragment` into the `project` manifest, preserving its formatting.
+///
+/// Tables are merged recursively and keys missing from the project are added.
+/// Existing values are kept, except that dependency versions follow `policy`
+/// and dependency feature lists are unioned.
+pub fn merge(project: &str, fragment: &str, policy: VersionConflict) -> anyhow::Result<Merged> {
+    let mut doc: DocumentMut = project.parse().context("Could not parse Cargo.toml")?;
+    let fragment: DocumentMut = fragment.parse()
+        .with_context(|| format!("Could not parse {FRAGMENT_FILE_NAME}"))?;
+
+    let mut merger = Merger { policy, path: Vec::new(), notes: Vec::new() };
+    merger.merge_table(doc.as_table_mut(), fragment.as_table())?;
+
+    Ok(Merged { contents: doc.to_string(), notes: merger.notes })
+}
+
+/// Make the dependencies of a workspace `member` manifest that the `root`
+/// manifest declares in `[workspace.dependencies]` inherit them with
+/// `workspace = true`, keeping their features and whether they're optional.
+pub fn inherit_workspace_dependencies(member: &str, root: &str) -> anyhow::Result<Merged> {
+    let mut doc: DocumentMut = member.parse().context("Could not parse Cargo.toml")?;
+    let root: DocumentMut = root.parse().context("Could not parse the workspace's Cargo.toml")?;
+    let shared = root.get("workspace")
+        .and_then(|workspace| workspace.get("dependencies"))
+        .and_then(Item::as_table_like);
+    let Some(shared) = shared else {
+        return Ok(Merged { contents: doc.to_string(), notes: Vec::new() });
+    };
+
+    let mut notes = Vec::new();
+    for table in DEPENDENCY_TABLES {
+        let Some(dependencies) = doc.get_mut(table).and_then(Item::as_table_like_mut) else {
+            continue;
+        };
+        for (name, dependency) in dependencies.iter_mut() {
+            let name = name.get();
+            // Renamed dependencies may be a different crate than the workspace's.
+            if !shared.contains_key(name) || dependency.get("package").is_some() {
+                continue;
+            }
+            if dependency.get("workspace").and_then(Item::as_bool) == Some(true) {
+                continue;
+            }
+
+            let mut inherited = InlineTable::new();
+            inherited.insert("workspace", true.into());
+            if let Some(table) = dependency.as_table_like() {
+                for key in ["features", "optional"] {
+                    if let Some(value) = table.get(key).and_then(Item::as_value) {
+                        inherited.insert(key, value.clone().decorated(" ", " "));
+                    }
+                }
+            }
+            *dependency = Item::Value(Value::InlineTable(inherited));
+            notes.push(format!("`{table}.{name}` now uses the workspace's version"));
+        }
+    }
+
+    Ok(Merged { contents: doc.to_string(), notes })
+}
+
+struct Merger {
+    policy: VersionConflict,
+    path: Vec<String>,
+    notes: Vec<String>,
+}
+
+impl Merger {
+    fn merge_table(&mut self, dest: &mut dyn TableLike, src: &dyn TableLike) -> anyhow::Result<()> {
+        let in_dependencies = self.path.last()
+            .is_some_and(|table| DEPENDENCY_TABLES.contains(&table.as_str()));
+
+        for (key, item) in src.iter() {
+            self.path.push(key.to_owned());
+            if !dest.contains_key(key) {
+                dest.insert(key, detached(item));
+            } else {
+                let existing = dest.get_mut(key).expect("key is present");
+                if in_dependencies {
+                    self.merge_dependency(key, existing, item)?;
+                } else if let (Some(dest), Some(src)) = (existing.as_table_like_mut(), item.as_table_like()) {
+                    self.merge_table(dest, src)?;
+                } else if !same_value(existing, item) {
+                    self.notes.push(format!("kept existing value of `{}`", self.path.join(".")));
+                }
+            }
+            self.path.pop();
+        }
+
+        Ok(())
+    }
+
+    fn merge_dependency(&mut self, name: &str, existing: &mut Item, incoming: &Item) -> anyhow::Result<()> {
+        let path = self.path.join(".");
+
+        if let (Some(ours), Some(theirs)) = (version_of(existing), version_of(incoming)) {
+            if ours != theirs {
+                let use_theirs = match self.policy {
+                    VersionConflict::Keep => false,
+                    VersionConflict::Bump => minimum(&theirs)
+                        .with_context(|| format!("`{path}`: invalid version requirement {theirs:?}"))?
+                        > minimum(&ours)
+                            .with_context(|| format!("`{path}`: invalid version requirement {ours:?}"))?,
+                    VersionConflict::Error => anyhow::bail!(
+                        "`{path}`: project requires {name} {ours:?} but the preset requires {theirs:?}"
+                    ),
+                };
+
+                if use_theirs {
+                    set_version(existing, &theirs);
+                    self.notes.push(format!("bumped `{path}` from {ours:?} to {theirs:?}"));
+                } else {
+                    self.notes.push(format!("kept `{path}` at {ours:?} (preset requires {theirs:?})"));
+                }
+            }
+        }
+
+        let Some(incoming) = incoming.as_table_like() else {
+            return Ok(());
+        };
+
+        if existing.is_str() && incoming.iter().any(|(key, _)| key != "version") {
+            expand(existing);
+        }
+        let Some(dest) = existing.as_table_like_mut() else {
+            return Ok(());
+        };
+
+        for (key, item) in incoming.iter() {
+            match key {
+                "version" => {}
+                "features" => union_features(dest, item),
+                _ if !dest.contains_key(key) => {
+                    dest.insert(key, detached(item));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn same_value(a: &Item, b: &Item) -> bool {
+    match (a.as_value(), b.as_value()) {
+        (Some(a), Some(b)) => {
+            a.clone().decorated("", "").to_string() == b.clone().decorated("", "").to_string()
+        }
+        _ => false,
+    }
+}
+
+/// Version requirement of a dependency written either as a string or a table.
+fn version_of(dependency: &Item) -> Option<String> {
+    match dependency.as_str() {
+        Some(version) => Some(version.to_owned()),
+        None => dependency.as_table_like()?
+            .get("version")?
+            .as_str()
+            .map(str::to_owned),
+    }
+}
+
+fn set_version(dependency: &mut Item, version: &str) {
+    let target = match dependency.as_table_like_mut() {
+        Some(table) => table.get_mut("version").and_then(Item::as_value_mut),
+        None => dependency.as_value_mut(),
+    };
+    if let Some(value) = target {
+        let decor = value.decor().clone();
+        *value = version.into();
+        *value.decor_mut() = decor;
+    }
+}
+
+/// Turn `dep = "1.0"` into `dep = { version = "1.0" }` so more keys can be added.
+fn expand(dependency: &mut Item) {
+    let Some(value) = dependency.as_value() else {
+        return;
+    };
+    let Some(version) = value.as_str() else {
+        return;
+    };
+
+    let mut table = InlineTable::new();
+    table.insert("version", version.into());
+    let mut expanded = Value::InlineTable(table);
+    *expanded.decor_mut() = value.decor().clone();
+    *dependency = Item::Value(expanded);
+}
+
+fn union_features(dest: &mut dyn TableLike, incoming: &Item) {
+    let Some(incoming) = incoming.as_array() else {
+        return;
+    };
+
+    match dest.get_mut("features").and_then(Item::as_array_mut) {
+        Some(features) => {
+            for feature in incoming.iter().filter_map(Value::as_str) {
+                if !features.iter().any(|f| f.as_str() == Some(feature)) {
+                    features.push(feature);
+                }
+            }
+        }
+        None => {
+            dest.insert("features", Item::Value(Value::Array(incoming.clone())));
+        }
+    }
+}
+
+/// Lowest version matched by the first comparator of `req`, for ordering requirements.
+fn minimum(req: &str) -> anyhow::Result<(u64, u64, u64)> {
+    let req = VersionReq::parse(req)?;
+    Ok(req.comparators.first()
+        .map(|c| (c.major, c.minor.unwrap_or(0), c.patch.unwrap_or(0)))
+        .unwrap_or_default())
+}
+
+/// Copy of `item` without the fragment's table positions, so it is appended
+/// after the project's existing tables.
+fn detached(item: &Item) -> Item {
+    let mut item = item.clone();
+    clear_positions(&mut item);
+    item
+}
+
+fn clear_positions(item: &mut Item) {
+    if let Some(table) = item.as_table_mut() {
+        table.set_position(None);
+        for (_, child) in table.iter_mut() {
+            clear_positions(child);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROJECT: &str = r#"[package]
+name = "demo"
+
+[dependencies]
+serde = "1.0"
+tokio = { version = "1.20", features = ["rt"] }
+"#;
+
+    fn dependency(contents: &str, name: &str) -> Item {
+        let doc: DocumentMut = contents.parse().unwrap();
+        doc["dependencies"][name].clone()
+    }
+
+    fn features(dependency: &Item) -> Vec<String> {
+        dependency["features"].as_array().unwrap()
+            .iter()
+            .map(|feature| feature.as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn keep_leaves_versions_alone() {
+        let merged = merge(PROJECT, "[dependencies]\nserde = \"1.2\"\n", VersionConflict::Keep).unwrap();
+        assert_eq!(version_of(&dependency(&merged.contents, "serde")).as_deref(), Some("1.0"));
+        assert_eq!(merged.notes.len(), 1);
+    }
+
+    #[test]
+    fn bump_takes_the_higher_version() {
+        let fragment = "[dependencies]\nserde = \"1.2\"\ntokio = { version = \"1.10\" }\n";
+        let merged = merge(PROJECT, fragment, VersionConflict::Bump).unwrap();
+        assert_eq!(version_of(&dependency(&merged.contents, "serde")).as_deref(), Some("1.2"));
+        assert_eq!(version_of(&dependency(&merged.contents, "tokio")).as_deref(), Some("1.20"));
+    }
+
+    #[test]
+    fn error_fails_on_differing_versions() {
+        let Err(err) = merge(PROJECT, "[dependencies]\nserde = \"1.2\"\n", VersionConflict::Error) else {
+            panic!("differing versions were merged");
+        };
+        assert!(err.to_string().contains("serde"), "{err}");
+        assert!(merge(PROJECT, "[dependencies]\nserde = \"1.0\"\n", VersionConflict::Error).is_ok());
+    }
+
+    #[test]
+    fn features_of_string_dependencies_are_added() {
+        let fragment = "[dependencies]\nserde = { version = \"1.0\", features = [\"derive\"] }\n";
+        let merged = merge(PROJECT, fragment, VersionConflict::Keep).unwrap();
+        let serde = dependency(&merged.contents, "serde");
+        assert_eq!(version_of(&serde).as_deref(), Some("1.0"));
+        assert_eq!(features(&serde), ["derive"]);
+    }
+
+    #[test]
+    fn features_of_table_dependencies_are_unioned() {
+        let fragment = "[dependencies]\ntokio = { version = \"1.20\", features = [\"rt\", \"macros\"] }\n";
+        let merged = merge(PROJECT, fragment, VersionConflict::Keep).unwrap();
+        assert_eq!(features(&dependency(&merged.contents, "tokio")), ["rt", "macros"]);
+    }
+
+    #[test]
+    fn missing_dependencies_and_keys_are_added() {
+        let fragment = "[package]\nname = \"other\"\nedition = \"2021\"\n\n[dependencies]\nanyhow = \"1\"\n";
+        let merged = merge(PROJECT, fragment, VersionConflict::Keep).unwrap();
+        let doc: DocumentMut = merged.contents.parse().unwrap();
+        assert_eq!(doc["package"]["name"].as_str(), Some("demo"));
+        assert_eq!(doc["package"]["edition"].as_str(), Some("2021"));
+        assert_eq!(doc["dependencies"]["anyhow"].as_str(), Some("1"));
+        assert!(merged.contents.contains("serde = \"1.0\"\n"));
+    }
+}
@@ -0,0 +1,102 @@
+use std::path::{Component, Path};
+use std::process::Command;
+
+use tempfile::TempDir;
+
+/// A repository cloned into a temporary directory, removed when dropped.
+pub struct Checkout {
+    dir: TempDir,
+    /// Commit that is checked out.
+    pub commit: String,
+}
+
+impl Checkout {
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+/// Clone `url`, which may also be a local path, and check out `rev` or the
+/// default branch.
+pub fn checkout(url: &str, rev: Option<&str>) -> anyhow::Result<Checkout> {
+    let dir = tempfile::tempdir()?;
+    git(None, &["clone", "--quiet", "--", url, &dir.path().to_string_lossy()])?;
+    if let Some(rev) = rev {
+        // A fresh clone only has remote branches, try them before tags and commits.
+        let commit = git(Some(dir.path()), &["rev-parse", "--verify", "--quiet", &format!("origin/{rev}^{{commit}}")])
+            .or_else(|_| git(Some(dir.path()), &["rev-parse", "--verify", "--quiet", &format!("{rev}^{{commit}}")]))
+            .map_err(|_| anyhow::anyhow!("{url} has no branch, tag or commit {rev}"))?;
+        git(Some(dir.path()), &["checkout", "--quiet", "--detach", &commit])?;
+    }
+
+    let commit = git(Some(dir.path()), &["rev-parse", "HEAD"])?;
+    Ok(Checkout { dir, commit })
+}
+
+/// Check that `subdir` is a directory inside a repository's checkout.
+pub fn check_subdir(subdir: &Path) -> anyhow::Result<()> {
+    if subdir.as_os_str().is_empty()
+        || !subdir.components().all(|component| matches!(component, Component::Normal(_)))
+    {
+        anyhow::bail!(format!("--subdir {} must be a relative path inside the repository", subdir.display()));
+    }
+    Ok(())
+}
+
+/// `url` made absolute if it points to a local repository, so that it can
+/// still be cloned from another directory.
+pub fn absolute_url(url: &str) -> anyhow::Result<String> {
+    let absolute = |path: &str| {
+        std::fs::canonicalize(path)
+            .map(|path| path.to_string_lossy().into_owned())
+            .map_err(|err| anyhow::anyhow!("Could not find repository {url}: {err}"))
+    };
+    if let Some(path) = url.strip_prefix("file://") {
+        Ok(format!("file://{}", absolute(path)?))
+    } else if !url.contains("://") && Path::new(url).exists() {
+        absolute(url)
+    } else {
+        Ok(url.to_owned())
+    }
+}
+
+/// Name of the repository at `url`, its last path component without `.git`.
+pub fn repo_name(url: &str) -> Option<&str> {
+    let name = url.trim_end_matches(['/', '\\']).rsplit(['/', '\\', ':']).next()?;
+    let name = name.strip_suffix(".git").unwrap_or(name);
+    (!name.is_empty()).then_some(name)
+}
+
+/// Run git with `args`, in `dir` if given, and return its trimmed output.
+fn git(dir: Option<&Path>, args: &[&str]) -> anyhow::Result<String> {
+    let mut command = Command::new("git");
+    if let Some(dir) = dir {
+        command.arg("-C").arg(dir);
+    }
+
+    let output = command.args(args)
+        .output()
+        .map_err(|err| anyhow::anyhow!("Could not run git: {err}"))?;
+    if !output.status.success() {
+        anyhow::bail!(format!(
+            "git {} failed: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_subdir_rejects_paths_outside_of_the_repository() {
+        for subdir in ["", "..", "../other", "presets/../../other", "/etc", "./presets"] {
+            assert!(check_subdir(Path::new(subdir)).is_err(), "{subdir:?} was accepted");
+        }
+        check_subdir(Path::new("presets/rust")).unwrap();
+    }
+}
@@ -0,0 +1,250 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::digest;
+use crate::lockfile::{self, Lockfile};
+use crate::store;
+
+/// Directory inside a project where cargo-preset keeps its state.
+pub const STATE_DIR: &str = ".cargo-preset";
+
+const ENTRY_FILE_NAME: &str = "entry.toml";
+const BACKUP_DIR: &str = "backup";
+
+/// Record of every apply in a project, used to undo them.
+pub struct Journal {
+    project: PathBuf,
+    dir: PathBuf,
+}
+
+/// One apply, stored in `.cargo-preset/journal/<id>/entry.toml` next to
+/// backups of the files it replaced.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Entry {
+    pub id: u64,
+    pub presets: Vec<String>,
+    #[serde(default)]
+    pub files: Vec<Change>,
+    /// Directories the apply created, relative to the project.
+    #[serde(default)]
+    pub created_dirs: Vec<PathBuf>,
+}
+
+/// A file written by an apply.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Change {
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+    /// Hash of the contents that were written, or removed for [`ChangeKind::Removed`].
+    pub hash: String,
+    /// Where the replaced file was moved to, for [`ChangeKind::BackedUp`].
+    pub backup: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChangeKind {
+    /// The file didn't exist before.
+    Created,
+    /// The file replaced one that is kept in the journal.
+    Overwritten,
+    /// The file replaced one that was renamed next to it.
+    BackedUp,
+    /// The file was deleted and is kept in the journal.
+    Removed,
+}
+
+impl Journal {
+    pub fn open(project: &Path) -> Self {
+        Self {
+            project: project.to_owned(),
+            dir: project.join(STATE_DIR).join("journal"),
+        }
+    }
+
+    /// All entries, oldest first.
+    pub fn entries(&self) -> anyhow::Result<Vec<Entry>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = Vec::new();
+        for dir in fs::read_dir(&self.dir)? {
+            let path = dir?.path().join(ENTRY_FILE_NAME);
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            let entry: Entry = toml::from_str(&contents)
+                .with_context(|| format!("Invalid journal entry {}", path.display()))?;
+            let paths = entry.files.iter()
+                .flat_map(|change| [Some(&change.path), change.backup.as_ref()])
+                .flatten()
+                .chain(&entry.created_dirs);
+            for rel in paths {
+                if !is_inside(rel) {
+                    anyhow::bail!(format!("Invalid journal entry {}: {} is not inside the project", path.display(), rel.display()));
+                }
+            }
+            entries.push(entry);
+        }
+        entries.sort_by_key(|entry| entry.id);
+        Ok(entries)
+    }
+
+    /// Store a new entry, moving the replaced files from `backups` into it.
+    pub fn record(
+        &self,
+        presets: &[String],
+        files: Vec<Change>,
+        created_dirs: Vec<PathBuf>,
+        backups: &Path,
+    ) -> anyhow::Result<u64> {
+        let id = self.entries()?.last().map_or(1, |entry| entry.id + 1);
+        let dir = self.dir.join(id.to_string());
+        fs::create_dir_all(&dir)?;
+
+        if backups.exists() {
+            fs::rename(backups, dir.join(BACKUP_DIR))?;
+        }
+
+        let entry = Entry { id, presets: presets.to_vec(), files, created_dirs };
+        fs::write(dir.join(ENTRY_FILE_NAME), toml::to_string(&entry)?)?;
+        Ok(id)
+    }
+
+    /// Revert the files written by `entry` and forget it. `later` are the
+    /// entries recorded after it.
+    ///
+    /// Files that changed since they were written are left alone and reported,
+    /// unless `force` is set. Lockfile entries and base snapshots are only
+    /// reverted for presets that weren't applied again later, as those now
+    /// belong to the later apply.
+    pub fn undo(&self, entry: &Entry, later: &[Entry], force: bool) -> anyhow::Result<()> {
+        let reapplied: Vec<_> = later.iter().flat_map(|entry| &entry.presets).collect();
+        let undone: Vec<_> = entry.presets.iter()
+            .filter(|name| !reapplied.contains(name))
+            .collect();
+        let files: Vec<_> = entry.files.iter()
+            .filter(|change| !store::is_project_state(&change.path) || undone.iter().any(|name| {
+                change.path.starts_with(lockfile::base_path(name, Path::new("")))
+            }))
+            .collect();
+
+        let modified: Vec<_> = files.iter()
+            .filter(|change| !store::is_project_state(&change.path) && self.is_modified(change))
+            .map(|change| change.path.display().to_string())
+            .collect();
+        if !modified.is_empty() && !force {
+            anyhow::bail!(
+                "Files changed since the apply, nothing was undone (use --force to undo anyway):\n\t{}",
+                modified.join("\n\t")
+            );
+        }
+
+        let backups = self.dir.join(entry.id.to_string()).join(BACKUP_DIR);
+        for change in files.iter().rev() {
+            let dest = self.project.join(&change.path);
+            match change.kind {
+                ChangeKind::Created => {
+                    if dest.exists() {
+                        fs::remove_file(&dest)?;
+                    }
+                }
+                ChangeKind::Overwritten | ChangeKind::Removed => {
+                    if let Some(parent) = dest.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::rename(backups.join(&change.path), &dest)
+                        .with_context(|| format!("Could not restore {}", change.path.display()))?;
+                }
+                ChangeKind::BackedUp => {
+                    let backup = change.backup.as_ref().map(|backup| self.project.join(backup));
+                    match backup {
+                        Some(backup) if backup.exists() => fs::rename(backup, &dest)?,
+                        _ => eprintln!("Backup of {} is gone, leaving it as is", change.path.display()),
+                    }
+                }
+            }
+        }
+
+        // The lockfile as it was before the apply is among its backups, unless
+        // the apply created it or left it as it was.
+        let locked = entry.files.iter().find(|change| change.path == Path::new(lockfile::FILE_NAME));
+        if let Some(change) = locked {
+            let before = match change.kind {
+                ChangeKind::Overwritten => Lockfile::load(&backups)?,
+                _ => Lockfile::default(),
+            };
+            let mut lockfile = Lockfile::load(&self.project)?;
+            for name in undone {
+                match before.get(name) {
+                    Some(preset) => lockfile.insert(preset.clone()),
+                    None => lockfile.presets.retain(|preset| preset.name != *name),
+                }
+            }
+            lockfile.save(&self.project)?;
+        }
+
+        for dir in entry.created_dirs.iter().rev() {
+            let _ = fs::remove_dir(self.project.join(dir));
+        }
+
+        fs::remove_dir_all(self.dir.join(entry.id.to_string()))?;
+        Ok(())
+    }
+
+    fn is_modified(&self, change: &Change) -> bool {
+        if change.kind == ChangeKind::Removed {
+            return self.project.join(&change.path).exists();
+        }
+        match fs::read(self.project.join(&change.path)) {
+            Ok(contents) => digest::sha256(&contents) != change.hash,
+            // A deleted file only counts as modified if there's something to restore.
+            Err(_) => change.kind != ChangeKind::Created,
+        }
+    }
+}
+
+/// Whether the relative `path` stays inside the directory it is relative to,
+/// being made of plain names only.
+pub fn is_inside(path: &Path) -> bool {
+    path.components().next().is_some()
+        && path.components().all(|component| matches!(component, Component::Normal(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_inside_accepts_plain_relative_paths_only() {
+        for path in ["", "..", "../a", "a/../../b", "/etc/passwd", "./a"] {
+            assert!(!is_inside(Path::new(path)), "{path:?} was accepted");
+        }
+        for path in ["a", "src/main.rs", ".cargo-preset/base/ci/a.yml"] {
+            assert!(is_inside(Path::new(path)), "{path:?} was rejected");
+        }
+    }
+
+    #[test]
+    fn entries_reject_paths_outside_of_the_project() {
+        let project = tempfile::tempdir().unwrap();
+        let journal = Journal::open(project.path());
+        let dir = journal.dir.join("1");
+        fs::create_dir_all(&dir).unwrap();
+
+        let entry = "id = 1\npresets = [\"a\"]\n\n[[files]]\npath = \"a.txt\"\nkind = \"backed-up\"\nhash = \"\"\n";
+        fs::write(dir.join(ENTRY_FILE_NAME), entry).unwrap();
+        assert_eq!(journal.entries().unwrap().len(), 1);
+
+        fs::write(dir.join(ENTRY_FILE_NAME), format!("{entry}backup = \"../../.bashrc\"\n")).unwrap();
+        assert!(journal.entries().is_err());
+
+        fs::write(dir.join(ENTRY_FILE_NAME), entry.replace("a.txt", "/home/me/a.txt")).unwrap();
+        assert!(journal.entries().is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::journal;
+use crate::store::PresetName;
+use crate::variables::Variables;
+
+/// Name of the lockfile written at the root of a project.
+pub const FILE_NAME: &str = "cargo-preset.lock";
+
+const HEADER: &str = "# This file is generated by cargo-preset. Do not edit it by hand.\n";
+
+/// Where the contents `preset` last rendered for `file` are kept, relative to the project.
+pub fn base_path(preset: &str, file: &Path) -> PathBuf {
+    Path::new(journal::STATE_DIR).join("base").join(preset).join(file)
+}
+
+/// Presets applied to a project and the files they wrote.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Lockfile {
+    #[serde(default, rename = "preset")]
+    pub presets: Vec<LockedPreset>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LockedPreset {
+    pub name: String,
+    pub version: Option<Version>,
+    /// Hash of the preset's contents when it was applied.
+    pub hash: String,
+    #[serde(default)]
+    pub variables: Variables,
+    /// Hash of every file the preset wrote, by path relative to the project.
+    #[serde(default)]
+    pub files: BTreeMap<String, String>,
+}
+
+impl Lockfile {
+    /// Load the lockfile in `project`, or an empty one if there is none.
+    pub fn load(project: &Path) -> anyhow::Result<Self> {
+        let path = project.join(FILE_NAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let lockfile: Self = toml::from_str(&contents)
+            .with_context(|| format!("Invalid {}", path.display()))?;
+        for preset in &lockfile.presets {
+            preset.name.parse::<PresetName>()
+                .with_context(|| format!("Invalid {}", path.display()))?;
+            if let Some(file) = preset.files.keys().find(|file| !journal::is_inside(Path::new(file))) {
+                anyhow::bail!(format!("Invalid {}: {file} is not inside the project", path.display()));
+            }
+        }
+        Ok(lockfile)
+    }
+
+    /// Write the lockfile to `project`, or remove it if no presets are left.
+    pub fn save(&self, project: &Path) -> anyhow::Result<()> {
+        let path = project.join(FILE_NAME);
+        if self.presets.is_empty() {
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+            return Ok(());
+        }
+        fs::write(&path, self.to_string()?)
+            .with_context(|| format!("Could not write {}", path.display()))
+    }
+
+    pub fn to_string(&self) -> anyhow::Result<String> {
+        Ok(format!("{HEADER}{}", toml::to_string(self)?))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&LockedPreset> {
+        self.presets.iter().find(|preset| preset.name == name)
+    }
+
+    /// Add `preset`, replacing an earlier apply of the same preset.
+    pub fn insert(&mut self, preset: LockedPreset) {
+        match self.presets.iter_mut().find(|locked| locked.name == preset.name) {
+            Some(locked) => *locked = preset,
+            None => self.presets.push(preset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_rejects_files_outside_of_the_project() {
+        let project = tempfile::tempdir().unwrap();
+        let lockfile = |file: &str| format!("[[preset]]\nname = \"ci\"\nhash = \"\"\n\n[preset.files]\n\"{file}\" = \"\"\n");
+
+        fs::write(project.path().join(FILE_NAME), lockfile(".github/workflows/ci.yml")).unwrap();
+        assert!(Lockfile::load(project.path()).is_ok());
+
+        for file in ["../../.bashrc", "/etc/passwd", "a/../../b", ""] {
+            fs::write(project.path().join(FILE_NAME), lockfile(file)).unwrap();
+            assert!(Lockfile::load(project.path()).is_err(), "{file:?} was accepted");
+        }
+    }
+}
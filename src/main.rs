@@ -1,9 +1,37 @@
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
 use std::fs;
 
-use clap::{Subcommand, Parser, Args};
+mod apply;
+mod bundle;
+mod capture;
+mod cargo_toml;
+mod digest;
+mod git;
+mod journal;
+mod lockfile;
+mod manifest;
+mod project;
+mod store;
+mod sync;
+mod template;
+mod update;
+mod variables;
+mod workspace;
+
+use anyhow::Context;
+use clap::{Subcommand, Parser, Args, ColorChoice, CommandFactory, FromArgMatches};
 use fs_extra::dir::CopyOptions;
 
+use apply::OnConflict;
+use cargo_toml::VersionConflict;
+use journal::Journal;
+use lockfile::Lockfile;
+use manifest::Manifest;
+use store::{PresetName, Store};
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
@@ -12,6 +40,19 @@ struct Cli {
     #[arg(short, long, default_value_t = false)]
     debug: bool,
 
+    /// Directory presets are kept in, defaults to $XDG_CONFIG_HOME/cargo_preset
+    /// or $HOME/.config/cargo_preset
+    #[arg(long, global = true, env = "CARGO_PRESET_HOME", value_name = "DIR")]
+    store: Option<PathBuf>,
+
+    /// Cargo.toml of the project to work on, instead of the one in the current directory
+    #[arg(long, global = true, value_name = "PATH")]
+    manifest_path: Option<PathBuf>,
+
+    /// Coloring of help and errors
+    #[arg(long, global = true, value_enum, env = "CARGO_TERM_COLOR", default_value_t)]
+    color: ColorChoice,
+
     #[command(subcommand)]
     command: Command,
 }
@@ -19,8 +60,81 @@ struct Cli {
 #[derive(Subcommand)]
 enum Command {
 
-    /// Use preset
-    Apply { name: String },
+    /// Use presets, rendering `*.tmpl` files as templates
+    Apply {
+        /// Presets to apply in order, later ones win when they share a file
+        #[arg(required = true)]
+        names: Vec<PresetName>,
+
+        /// How to resolve dependency versions that differ from the preset's Cargo.fragment.toml
+        #[arg(long, value_enum, default_value_t)]
+        version_conflict: VersionConflict,
+
+        /// What to do with existing files that differ from the preset
+        #[arg(long, value_enum, default_value_t)]
+        on_conflict: OnConflict,
+
+        /// List what would happen to every file without writing anything
+        #[arg(long)]
+        dry_run: bool,
+
+        #[command(flatten)]
+        var_args: VariableArgs,
+
+        /// Project to apply to, defaults to the one the current directory is in
+        #[arg(long, value_name = "DIR")]
+        into: Option<PathBuf>,
+
+        /// Apply to the root of the workspace the project belongs to
+        #[arg(long)]
+        workspace_root: bool,
+
+        /// Apply even if the directory isn't a cargo project
+        #[arg(long)]
+        force: bool,
+
+        /// Apply to every member of the workspace the project belongs to
+        #[arg(long, conflicts_with = "workspace_root")]
+        workspace: bool,
+
+        /// Only apply to workspace members whose package name matches this glob
+        #[arg(long, short, value_name = "PATTERN", requires = "workspace")]
+        package: Vec<String>,
+
+        /// Create a new workspace member at this path, relative to the workspace root,
+        /// from the presets and register it in the workspace
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["workspace", "workspace_root"])]
+        as_member: Option<PathBuf>,
+    },
+
+    /// Create a new crate with `cargo new` and apply presets to it
+    New {
+        /// Directory of the new crate
+        path: PathBuf,
+
+        /// Preset to apply, can be given several times, later ones win when they share a file
+        #[arg(long = "preset", value_name = "NAME")]
+        presets: Vec<PresetName>,
+
+        /// Create a library crate
+        #[arg(long, conflicts_with = "bin")]
+        lib: bool,
+
+        /// Create a binary crate, the default
+        #[arg(long)]
+        bin: bool,
+
+        /// How to resolve dependency versions that differ from the preset's Cargo.fragment.toml
+        #[arg(long, value_enum, default_value_t)]
+        version_conflict: VersionConflict,
+
+        /// What to do with files from `cargo new` that the presets also have
+        #[arg(long, value_enum, default_value_t = OnConflict::Overwrite)]
+        on_conflict: OnConflict,
+
+        #[command(flatten)]
+        var_args: VariableArgs,
+    },
 
     /// List available presets
     List,
@@ -28,151 +142,826 @@ enum Command {
     /// Add preset
     Add {
         /// Name of new preset
-        name: String,
+        name: PresetName,
 
         #[command(flatten)]
         paths: AddEntry,
+
+        /// Directory `--files` are stored relative to, defaults to the project root
+        #[arg(long)]
+        root: Option<PathBuf>,
+
+        /// Only capture project files matching these globs
+        #[arg(long, requires = "from_project")]
+        include: Vec<String>,
+
+        /// Never capture project files matching these globs
+        #[arg(long, requires = "from_project")]
+        exclude: Vec<String>,
+
+        /// List the files that would be captured without adding the preset
+        #[arg(long, requires = "from_project")]
+        preview: bool,
+    },
+
+    /// Import presets from a bundle made with `export`, or a preset from a git repository
+    Import {
+        /// Bundle to import, a .tar.gz, .tgz or .zip file
+        #[arg(required_unless_present = "git", conflicts_with = "git")]
+        bundle: Option<PathBuf>,
+
+        /// Repository to clone, which may be a local path or file:// url
+        #[arg(long, value_name = "URL")]
+        git: Option<String>,
+
+        /// Branch, tag or commit to import, defaults to the default branch
+        #[arg(long, conflicts_with = "bundle")]
+        rev: Option<String>,
+
+        /// Directory of the repository holding the preset
+        #[arg(long, conflicts_with = "bundle")]
+        subdir: Option<PathBuf>,
+
+        /// Name of the imported preset, defaults to the name of `--subdir` or the repository
+        #[arg(long, conflicts_with = "bundle")]
+        name: Option<PresetName>,
+
+        /// Replace presets that already exist
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Package presets, and the presets they extend, into a bundle to share them
+    Export {
+        #[arg(required = true)]
+        names: Vec<PresetName>,
+
+        /// Bundle to write, a .tar.gz, .tgz or .zip file
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+
+    /// Refresh presets imported from git with the latest contents of their repository
+    Sync {
+        /// Preset to sync, all imported presets if omitted
+        name: Option<PresetName>,
+
+        /// Hold the preset at this branch, tag or commit from now on
+        #[arg(long, requires = "name")]
+        pin: Option<String>,
+
+        /// Follow the repository's default branch again
+        #[arg(long, requires = "name", conflicts_with = "pin")]
+        unpin: bool,
+
+        /// Show what would change without updating the store
+        #[arg(long)]
+        dry_run: bool,
     },
 
     /// Remove preset
-    Remove { name: String },
+    Remove { name: PresetName },
+
+    /// View metadata and contents of preset
+    Inspect { name: PresetName },
+
+    /// Show presets applied to the current project and which of their files changed
+    Status,
+
+    /// Update presets applied to the current project to their latest contents,
+    /// merging in local changes
+    Update {
+        /// Preset to update, all applied presets if omitted
+        name: Option<PresetName>,
+
+        /// How to resolve dependency versions that differ from the preset's Cargo.fragment.toml
+        #[arg(long, value_enum, default_value_t)]
+        version_conflict: VersionConflict,
 
-    /// View details of preset
-    Inspect { name: String }
+        /// What to do with existing files that can't be merged
+        #[arg(long, value_enum, default_value_t)]
+        on_conflict: OnConflict,
+
+        /// List what would happen to every file without writing anything
+        #[arg(long)]
+        dry_run: bool,
+
+        #[command(flatten)]
+        var_args: VariableArgs,
+    },
+
+    /// Revert the most recent apply in the current project
+    Undo {
+        /// Journal id or preset name of the apply to revert instead
+        target: Option<String>,
+
+        /// Revert files even if they changed since the apply
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Args)]
+struct VariableArgs {
+
+    /// Value of a preset variable, can be given several times
+    #[arg(long = "define", short = 'D', value_name = "KEY=VALUE", value_parser = parse_define)]
+    defines: Vec<(String, String)>,
+
+    /// TOML file with values of preset variables
+    #[arg(long, value_name = "FILE")]
+    values: Option<PathBuf>,
+
+    /// Never ask for values of variables, fail instead if one has neither a value nor a default
+    #[arg(long)]
+    non_interactive: bool,
+}
+
+impl VariableArgs {
+    /// Values given for variables, `--define`s taking precedence over `--values`.
+    fn load(&self) -> anyhow::Result<variables::Variables> {
+        let mut vars = variables::Variables::new();
+        if let Some(path) = &self.values {
+            let contents = fs::read_to_string(path)
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            let values: toml::Table = contents.parse()
+                .with_context(|| format!("Invalid {}", path.display()))?;
+            vars.extend(values);
+        }
+        for (key, value) in &self.defines {
+            vars.insert(key.clone(), toml::Value::String(value.clone()));
+        }
+        Ok(vars)
+    }
+
+    /// Whether to ask for missing values, never when stdin isn't a terminal.
+    fn interactive(&self) -> bool {
+        !self.non_interactive && io::stdin().is_terminal()
+    }
+}
+
+fn parse_define(define: &str) -> anyhow::Result<(String, String)> {
+    match define.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => Ok((key.trim().to_owned(), value.to_owned())),
+        _ => anyhow::bail!(format!("expected KEY=VALUE, got {define:?}")),
+    }
 }
 
 #[derive(Args)]
 #[group(required = true)]
 struct AddEntry {
 
-    /// Files to have with preset
+    /// Files to have with preset, kept at their path relative to `--root`
     #[arg(long)]
     files: Vec<PathBuf>,
 
     /// Directories to have with preset
     #[arg(long)]
     directories: Vec<PathBuf>,
+
+    /// Capture a whole project, defaults to the current one. Honors .gitignore
+    /// and .presetignore, and leaves out target/ and .git/
+    #[arg(long, value_name = "PATH")]
+    from_project: Option<Option<PathBuf>>,
 }
 
 
+/// Parse the arguments, also when run by cargo as `cargo preset`, which
+/// passes the name of the subcommand along.
+fn parse_args() -> Cli {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let mut command = Cli::command();
+    if args.get(1).is_some_and(|arg| arg == "preset") {
+        args.remove(1);
+        command = command.bin_name("cargo preset");
+    }
+
+    // Help and errors are printed while parsing, so look for --color first.
+    let color = Cli::command()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+        .ok()
+        .and_then(|matches| matches.get_one::<ColorChoice>("color").copied())
+        .unwrap_or_default();
+
+    let matches = command.color(color).get_matches_from(args);
+    Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit())
+}
+
 fn main() -> anyhow::Result<()> {
-    let args = Cli::parse();
-    let mut config = if let Some(path) = std::env::var_os("HOME") {
-        let mut p: PathBuf = path.into();
-        p.push(".config");
-        if !p.exists() {
-            anyhow::bail!("$HOME/.config directory not found");
-        }
-        p.push("cargo_preset");
-        if !p.exists() {
-            if args.debug {
-                println!("Creating cargo_preset configuration directory");
-            }
-            let _ = fs::create_dir(&p)?;
-        }
-        p
-    } else {
-        anyhow::bail!("Home directory not found");
+    let args = parse_args();
+    // The project of --manifest-path, or the one the current directory is in.
+    let project_dir = || -> anyhow::Result<PathBuf> {
+        match &args.manifest_path {
+            Some(manifest_path) => project::manifest_dir(manifest_path),
+            None => {
+                let dir = std::env::current_dir()?;
+                Ok(project::find_root(&dir).unwrap_or(dir))
+            }
+        }
     };
 
-    let current_presets: Vec<_> = config.read_dir()?
-        .map(|dir| {
-            dir.expect("Could not get directory entry")
-                .file_name()
-                .to_str()
-                .expect("Could not convert filename into string")
-                .to_owned()
-        })
-        .collect();
+    let config = match &args.store {
+        Some(store) => store.clone(),
+        None => store::default_root()?,
+    };
+    if !config.exists() {
+        if args.debug {
+            println!("Creating preset store {}", config.display());
+        }
+        fs::create_dir_all(&config)
+            .with_context(|| format!("Could not create preset store {}", config.display()))?;
+    }
 
+    let project = project_dir()?;
+    let store = Store::new(config, Some(&project));
 
     match args.command {
-        Command::Apply { name } => {
-            config.push(&name);
-            if !current_presets.contains(&name) {
-                anyhow::bail!(format!("Could not find preset with name {name}"));
+        Command::Apply {
+            names, version_conflict, on_conflict, dry_run, var_args, into, workspace_root, force, workspace, package,
+            as_member,
+        } => {
+            let mut defined = var_args.load()?;
+            let interactive = var_args.interactive();
+            for name in &names {
+                if !store.contains(name) {
+                    anyhow::bail!(format!("Could not find preset with name {name}"));
+                }
+            }
+
+            let mut curr_dir = match into {
+                Some(into) => into.canonicalize()
+                    .with_context(|| format!("Could not find {}", into.display()))?,
+                None => project_dir()?,
+            };
+            if workspace_root {
+                curr_dir = project::workspace_root(&curr_dir)?;
+            }
+            if !curr_dir.join("Cargo.toml").is_file() && !force {
+                anyhow::bail!(format!(
+                    "{} is not a cargo project, use --force to apply to it anyway",
+                    curr_dir.display()
+                ));
+            }
+
+            if let Some(member) = as_member {
+                let names: Vec<String> = names.into_iter().map(String::from).collect();
+                let root = project::workspace_root(&curr_dir)?;
+                workspace::check_new_member(&root, &member)?;
+                // Worked out before creating anything, so a workspace that can't take
+                // the member leaves nothing behind.
+                let root_manifest = workspace::with_member(&root, &member)?;
+
+                let dir = root.join(&member);
+                let crate_name = member.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                let mut vars = variables::Variables::from([
+                    ("crate_name".to_owned(), toml::Value::String(crate_name.clone())),
+                    ("package_path".to_owned(), toml::Value::String(member.to_string_lossy().into_owned())),
+                ]);
+                vars.extend(defined);
+
+                // Plan in a scratch directory on a dry run, since the member doesn't exist yet.
+                let scratch = if dry_run { Some(tempfile::tempdir()?) } else { None };
+                let target = scratch.as_ref().map_or(dir.clone(), |scratch| scratch.path().to_owned());
+                let has_manifest = names.iter().try_fold(false, |found, name| -> anyhow::Result<bool> {
+                    Ok(found || store.layered_files(name)?.keys().any(|path| {
+                        path == Path::new("Cargo.toml") || path == Path::new("Cargo.toml.tmpl")
+                    }))
+                })?;
+
+                let applied = (|| -> anyhow::Result<()> {
+                    if has_manifest {
+                        fs::create_dir_all(&target)?;
+                    } else {
+                        workspace::write_skeleton(&target, &crate_name, &root)?;
+                    }
+                    let mut plan = apply::plan_presets(&store, &names, &target, &mut vars, interactive, version_conflict)?;
+                    workspace::inherit_dependencies(&mut plan, &root)?;
+                    if dry_run {
+                        plan.lock()?;
+                        plan.print(on_conflict);
+                        return Ok(());
+                    }
+                    plan.resolve(on_conflict)?;
+                    plan.lock()?;
+
+                    // Registering the member goes in the journal of the workspace root,
+                    // so that undoing there removes it from the members again.
+                    let mut root_plan = apply::Plan::new(root.clone());
+                    if let Some(contents) = &root_manifest {
+                        let file = template::RenderedFile { path: "Cargo.toml".into(), contents: contents.clone().into_bytes() };
+                        root_plan.add(file, None)?;
+                        root_plan.presets = plan.presets.clone();
+                    }
+                    plan.write()?;
+                    if !root_plan.files.is_empty() {
+                        root_plan.write()?;
+                    }
+                    Ok(())
+                })();
+                if let Err(err) = applied {
+                    if !dry_run && dir.exists() {
+                        fs::remove_dir_all(&dir)?;
+                    }
+                    return Err(err);
+                }
+
+                match (root_manifest, dry_run) {
+                    (Some(_), true) => println!("Would add {} to the workspace members", member.display()),
+                    (Some(_), false) => println!("Added {} to the workspace members", member.display()),
+                    (None, _) => {}
+                }
+                return Ok(());
             }
 
-            let curr_dir: PathBuf = std::str::from_utf8(
-                &std::process::Command::new("sh")
-                    .arg("-c")
-                    .arg("pwd")
-                    .output()?
-                    .stdout
-                )?
-                .trim()
-                .into();
+            // Every directory to apply to, with the variables specific to it.
+            let mut targets = Vec::new();
+            if workspace {
+                let (root, members) = project::workspace_members(&curr_dir)?;
+                let patterns = package.iter()
+                    .map(|pattern| Ok(globset::Glob::new(pattern)?.compile_matcher()))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                for member in members {
+                    if !patterns.is_empty() && !patterns.iter().any(|pattern| pattern.is_match(&member.name)) {
+                        continue;
+                    }
+                    let path = match member.dir.strip_prefix(&root) {
+                        Ok(rel) if !rel.as_os_str().is_empty() => rel.to_string_lossy().into_owned(),
+                        _ => ".".to_owned(),
+                    };
+                    let vars = variables::Variables::from([
+                        ("package_path".to_owned(), toml::Value::String(path)),
+                    ]);
+                    targets.push((Some(member.name), member.dir, vars));
+                }
+                if targets.is_empty() {
+                    anyhow::bail!(format!("No workspace members of {} match {}", root.display(), package.join(", ")));
+                }
+            } else {
+                targets.push((None, curr_dir, variables::Variables::new()));
+            }
 
-            fs_extra::dir::copy(&config, curr_dir, &CopyOptions::new().copy_inside(true).content_only(true))?;
-            config.pop();
+            // Work out every plan before writing anything, so a conflict in one
+            // member doesn't leave the others half applied.
+            let names: Vec<String> = names.into_iter().map(String::from).collect();
+            let mut plans = Vec::new();
+            for (member, dir, specific) in &targets {
+                if let Some(member) = member {
+                    println!("{member}:");
+                }
+                let mut vars = specific.clone();
+                vars.extend(defined.clone());
+                let mut plan = apply::plan_presets(&store, &names, dir, &mut vars, interactive, version_conflict)?;
+                // Values asked for are used for the following members too.
+                defined.extend(vars.into_iter().filter(|(var, _)| !specific.contains_key(var)));
+                if dry_run {
+                    plan.lock()?;
+                    plan.print(on_conflict);
+                } else {
+                    plan.resolve(on_conflict)?;
+                    plan.lock()?;
+                }
+                plans.push(plan);
+            }
+
+            if !dry_run {
+                for plan in plans {
+                    let id = plan.write()?;
+                    if args.debug {
+                        println!("Recorded apply #{id} in the journal of {}", plan.dir.display());
+                    }
+                }
+            }
+        }
+        Command::New { path, presets, lib, bin, version_conflict, on_conflict, var_args } => {
+            let mut defined = var_args.load()?;
+            // Catches missing presets and parents before creating anything.
+            for name in &presets {
+                store.layers(name)?;
+            }
+
+            let mut cargo_new = project::cargo();
+            cargo_new.arg("new");
+            if lib {
+                cargo_new.arg("--lib");
+            }
+            if bin {
+                cargo_new.arg("--bin");
+            }
+            let status = cargo_new.arg(&path)
+                .status()
+                .map_err(|err| anyhow::anyhow!("Could not run cargo: {err}"))?;
+            if !status.success() {
+                anyhow::bail!(format!("cargo new failed ({status})"));
+            }
+            if presets.is_empty() {
+                return Ok(());
+            }
+
+            let dir = path.canonicalize()?;
+            let names: Vec<String> = presets.into_iter().map(String::from).collect();
+            let applied = apply::plan_presets(&store, &names, &dir, &mut defined, var_args.interactive(), version_conflict).and_then(|mut plan| {
+                plan.resolve(on_conflict)?;
+                plan.lock()?;
+                plan.write()
+            });
+            match applied {
+                Ok(id) => {
+                    if args.debug {
+                        println!("Recorded apply #{id} in the journal");
+                    }
+                    println!("Applied {} to {}", names.join(", "), path.display());
+                }
+                Err(err) => {
+                    // The crate was only just created, so don't leave a half-set-up one behind.
+                    fs::remove_dir_all(&dir)?;
+                    return Err(err.context(format!("Could not apply presets, removed {}", path.display())));
+                }
+            }
         }
         Command::List => {
             println!("Available presets: ");
-            for dir in current_presets {
-                println!("\t{}", dir);
+            let mut shadowed = Vec::new();
+            for name in store.names()? {
+                let origins = store.origins(&name);
+                let Some((origin, _)) = origins.first() else {
+                    continue;
+                };
+                match store.manifest(&name) {
+                    Ok(Manifest { description: Some(description), .. }) => {
+                        println!("\t{name} ({origin}) - {description}");
+                    }
+                    Ok(_) => println!("\t{name} ({origin})"),
+                    Err(_) => println!("\t{name} ({origin}, invalid {})", manifest::FILE_NAME),
+                }
+                for (other, dir) in &origins[1..] {
+                    shadowed.push(format!(
+                        "warning: {name} from the {origin} store shadows the one in the {other} store at {}",
+                        dir.display()
+                    ));
+                }
+            }
+            for warning in shadowed {
+                eprintln!("{warning}");
             }
         }
-        Command::Add { name, paths } => {
-            if current_presets.contains(&name) {
+        Command::Add { name, paths, root, include, exclude, preview } => {
+            if store.contains(&name) {
                 anyhow::bail!(format!("Preset with name {name} already exists"));
             }
 
-            config.push(name);
+            let root = match root {
+                Some(root) => root,
+                None => project_dir()?,
+            };
+            let root = root.canonicalize()
+                .with_context(|| format!("Could not find {}", root.display()))?;
+
+            let mut files = paths.files.iter()
+                .map(|file| relative_to(&root, file).map(|rel| (file.clone(), rel)))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            files.retain(|(_, rel)| {
+                let state = store::is_project_state(rel);
+                if state {
+                    eprintln!("Leaving out {}, it is cargo-preset's own state", rel.display());
+                }
+                !state
+            });
+
+            if let Some(project) = paths.from_project {
+                let project = match project {
+                    Some(project) => project,
+                    None => project_dir()?,
+                };
+                let captured = capture::project_files(&project, &include, &exclude)?;
+                if preview {
+                    for rel in &captured {
+                        println!("{}", rel.display());
+                    }
+                    println!("{} files would be captured from {}", captured.len(), project.display());
+                    return Ok(());
+                }
+                files.extend(captured.into_iter().map(|rel| (project.join(&rel), rel)));
+            }
+
+            let config = store.path(&name);
             fs::create_dir(&config)?;
-            for file in paths.files {
-                eprintln!("file: {file:#?}, config: {config:#?}");
-                config.push(&file);
-                let r = fs::copy(file, &config)?;
-                eprintln!("wrote {r} bytes");
-                config.pop();
+            for (file, rel) in files {
+                let dest = config.join(&rel);
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let r = fs::copy(&file, &dest)
+                    .with_context(|| format!("Could not copy {}", file.display()))?;
+                if args.debug {
+                    println!("Copied {} ({r} bytes)", rel.display());
+                }
             }
 
             let opts = CopyOptions::new()
                 .copy_inside(true);
 
             for directory in paths.directories {
+                // Directories are copied by name into the preset's root.
+                let name = directory.file_name().map(Path::new).unwrap_or(Path::new(""));
+                if store::is_project_state(name) {
+                    eprintln!("Leaving out {}, it is cargo-preset's own state", directory.display());
+                    continue;
+                }
                 fs_extra::dir::copy(directory, &config, &opts)?;
             }
         }
+        Command::Import { bundle: Some(bundle), force, .. } => {
+            let files = bundle::read(&bundle)?;
+            let names: Vec<String> = bundle::presets(&files)?.into_iter().map(String::from).collect();
+            if !force {
+                let existing: Vec<_> = names.iter().filter(|name| store.contains(name)).cloned().collect();
+                if !existing.is_empty() {
+                    anyhow::bail!(format!(
+                        "Presets already exist, nothing was imported (use --force to replace them): {}",
+                        existing.join(", ")
+                    ));
+                }
+            }
+
+            for name in &names {
+                let dir = tempfile::tempdir()?;
+                bundle::extract(&files, name, dir.path())?;
+                let staged = store.stage(dir.path())?;
+                Manifest::load(staged.path())?;
+                store.commit(name, staged)?;
+            }
+            println!("Imported {}", names.join(", "));
+        }
+        Command::Import { git, rev, subdir, name, force, .. } => {
+            let git = git::absolute_url(&git.expect("clap requires a bundle or --git"))?;
+            if let Some(subdir) = &subdir {
+                git::check_subdir(subdir)?;
+            }
+            let name = match name {
+                Some(name) => name,
+                None => subdir.as_deref()
+                    .and_then(Path::file_name)
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .or_else(|| git::repo_name(&git).map(str::to_owned))
+                    .context("Could not tell the preset's name from the repository, pass --name")?
+                    .parse::<PresetName>()
+                    .context("Could not tell the preset's name from the repository, pass --name")?,
+            };
+            if store.contains(&name) && !force {
+                anyhow::bail!(format!("Preset with name {name} already exists, use --force to replace it"));
+            }
+
+            let checkout = git::checkout(&git, rev.as_deref())?;
+            let src = match &subdir {
+                Some(subdir) => checkout.path().join(subdir),
+                None => checkout.path().to_owned(),
+            };
+            if !src.is_dir() {
+                anyhow::bail!(format!("{} has no directory {}", git, subdir.unwrap_or_default().display()));
+            }
+
+            let staged = store.stage(&src)?;
+            let source = manifest::Source { git, rev, subdir, commit: checkout.commit };
+            manifest::set_source(staged.path(), &source)?;
+            Manifest::load(staged.path())?;
+            store.commit(&name, staged)?;
+            println!("Imported {name} at {}", source.commit);
+        }
+        Command::Export { names, output } => {
+            let mut presets = Vec::new();
+            for name in &names {
+                for layer in store.layers(name)? {
+                    if !presets.contains(&layer) {
+                        presets.push(layer);
+                    }
+                }
+            }
+
+            bundle::export(&store, &presets, &output)?;
+            println!("Exported {} to {}", presets.join(", "), output.display());
+        }
+        Command::Sync { name, pin, unpin, dry_run } => {
+            let pin = if unpin { Some(None) } else { pin.map(Some) };
+            let names = match name {
+                Some(name) => {
+                    if !store.contains(&name) {
+                        anyhow::bail!(format!("Could not find preset with name {name}"));
+                    }
+                    vec![name.into()]
+                }
+                None => store.names()?.into_iter()
+                    .filter(|name| store.origins(name).first().is_some_and(|(origin, _)| *origin == store::Origin::User))
+                    .filter(|name| matches!(store.manifest(name), Ok(Manifest { source: Some(_), .. })))
+                    .collect(),
+            };
+            if names.is_empty() {
+                println!("No presets were imported from git");
+            }
+
+            for name in names {
+                sync::sync(&store, &name, pin.clone(), dry_run)?;
+            }
+        }
         Command::Remove { name } => {
-            config.push(name);
-            fs::remove_dir_all(config)?;
+            store.remove(&name)?;
         }
         Command::Inspect { name } => {
-            config.push(&name);
+            if !store.contains(&name) {
+                anyhow::bail!(format!("Could not find preset with name {name}"));
+            }
+            print_manifest(&name, &store.manifest(&name)?);
             println!("Contents of {name}: ");
-            for dir in fs::read_dir(&config)? {
-                let dir = dir.unwrap();
-                if dir.metadata()?.is_dir() {
-                    println!("- {}/", dir.file_name().to_str().unwrap());
-                    print_dir(2, &dir.path())?;
+            print_tree(&name, &store.layered_files(&name)?);
+        }
+        Command::Status => {
+            let dir = project_dir()?;
+            let lockfile = Lockfile::load(&dir)?;
+            if lockfile.presets.is_empty() {
+                println!("No presets applied in {}", dir.display());
+            }
+
+            for preset in &lockfile.presets {
+                let state = if !store.contains(&preset.name) {
+                    "no longer available"
+                } else if store.content_hash(&preset.name)? != preset.hash {
+                    "preset changed since apply"
                 } else {
-                    println!("- {}", dir.file_name().to_str().unwrap());
+                    "up to date"
+                };
+                match &preset.version {
+                    Some(version) => println!("{} {version} ({state})", preset.name),
+                    None => println!("{} ({state})", preset.name),
+                }
+
+                for (path, hash) in &preset.files {
+                    let file_state = match fs::read(dir.join(path)) {
+                        Ok(contents) if digest::sha256(&contents) == *hash => "pristine",
+                        Ok(_) => "modified",
+                        Err(_) => "missing",
+                    };
+                    println!("\t{file_state:<9} {path}");
                 }
             }
         }
+        Command::Update { name, version_conflict, on_conflict, dry_run, var_args } => {
+            let mut defined = var_args.load()?;
+            let dir = project_dir()?;
+            let names = match name {
+                Some(name) => vec![name.into()],
+                None => Lockfile::load(&dir)?.presets.into_iter().map(|preset| preset.name).collect(),
+            };
+            if names.is_empty() {
+                println!("No presets applied in {}", dir.display());
+            }
+
+            let mut plan = update::plan_update(&store, &dir, &names, &mut defined, var_args.interactive(), version_conflict)?;
+            if plan.presets.is_empty() {
+                return Ok(());
+            }
+            if dry_run {
+                plan.lock()?;
+                plan.print(on_conflict);
+            } else {
+                plan.resolve(on_conflict)?;
+                plan.lock()?;
+                plan.write()?;
+
+                let conflicted: Vec<_> = plan.files.iter()
+                    .filter(|file| file.status == apply::Status::Conflicted)
+                    .map(|file| file.path.display().to_string())
+                    .collect();
+                if !conflicted.is_empty() {
+                    println!("Conflict markers were written to:\n\t{}", conflicted.join("\n\t"));
+                }
+            }
+        }
+        Command::Undo { target, force } => {
+            let dir = project_dir()?;
+            let journal = Journal::open(&dir);
+            let mut entries = journal.entries()?;
+            let index = match &target {
+                None => entries.len().checked_sub(1),
+                Some(target) => entries.iter().rposition(|entry| {
+                    entry.id.to_string() == *target || entry.presets.contains(target)
+                }),
+            };
+            let Some(index) = index else {
+                match target {
+                    Some(target) => anyhow::bail!(format!("No apply of {target} found in {}", dir.display())),
+                    None => anyhow::bail!(format!("Nothing to undo in {}", dir.display())),
+                }
+            };
+
+            let later = entries.split_off(index + 1);
+            let entry = entries.swap_remove(index);
+            journal.undo(&entry, &later, force)?;
+            println!("Reverted apply #{} of {}", entry.id, entry.presets.join(", "));
+        }
     }
 
     Ok(())
 }
 
-fn print_dir(level: usize, path: &PathBuf) -> anyhow::Result<()> {
-    for file in fs::read_dir(path)? {
-        let file = file.unwrap();
-        if file.metadata()?.is_dir() {
-            println!("{} {}/", 
-            "-".repeat(level),
-            &file.file_name().to_str().unwrap()
-        );
-            print_dir(level + 1, &file.path())?;
-        } else {
-            println!(
-            "{} {}",
-            "-".repeat(level),
-            file.file_name().to_str().unwrap()
-        );
+/// Path of `file` relative to `root`, which must contain it.
+fn relative_to(root: &Path, file: &Path) -> anyhow::Result<PathBuf> {
+    let name = file.file_name()
+        .with_context(|| format!("{} is not a file", file.display()))?;
+    let parent = match file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let parent = parent.canonicalize()
+        .with_context(|| format!("Could not find {}", file.display()))?;
+
+    match parent.strip_prefix(root) {
+        Ok(rel) => Ok(rel.join(name)),
+        Err(_) => anyhow::bail!(format!("{} is outside of {}", file.display(), root.display())),
+    }
+}
+
+fn print_manifest(name: &str, manifest: &Manifest) {
+    match &manifest.version {
+        Some(version) => println!("{name} {version}"),
+        None => println!("{name}"),
+    }
+    if let Some(description) = &manifest.description {
+        println!("{description}");
+    }
+    if !manifest.authors.is_empty() {
+        println!("Authors: {}", manifest.authors.join(", "));
+    }
+    if !manifest.tags.is_empty() {
+        println!("Tags: {}", manifest.tags.join(", "));
+    }
+    if !manifest.extends.is_empty() {
+        println!("Extends: {}", manifest.extends.join(", "));
+    }
+    if let Some(source) = &manifest.source {
+        println!("Source: {} at {}", source.git, source.commit);
+    }
+    if let Some(version) = &manifest.min_cargo_preset_version {
+        println!("Requires cargo-preset >= {version}");
+    }
+    if !manifest.variables.is_empty() {
+        println!("Variables: ");
+        for (var, spec) in &manifest.variables {
+            let default = spec.default.as_ref()
+                .map(|default| format!(" (default: {default})"))
+                .unwrap_or_default();
+            match &spec.description {
+                Some(description) => println!("\t{var}{default} - {description}"),
+                None => println!("\t{var}{default}"),
+            }
         }
     }
+    println!();
+}
 
-    Ok(())
+/// Print `files` as a tree, noting the ones inherited from or overriding
+/// presets that `name` extends.
+fn print_tree(name: &str, files: &BTreeMap<PathBuf, Vec<String>>) {
+    let mut printed = BTreeSet::new();
+    for (path, layers) in files {
+        let dirs: Vec<_> = path.ancestors()
+            .skip(1)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .collect();
+        for dir in dirs.into_iter().rev() {
+            if printed.insert(dir) {
+                let level = dir.components().count();
+                println!("{} {}/", "-".repeat(level), dir.file_name().unwrap().to_string_lossy());
+            }
+        }
+
+        let (layer, overridden) = layers.split_last().expect("files come from at least one layer");
+        let origin = if layer != name {
+            format!(" (from {layer})")
+        } else if !overridden.is_empty() {
+            format!(" (overrides {})", overridden.join(", "))
+        } else {
+            String::new()
+        };
+        let level = path.components().count();
+        println!("{} {}{origin}", "-".repeat(level), path.file_name().unwrap().to_string_lossy());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_to_rejects_files_outside_of_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap().join("project");
+        fs::create_dir_all(root.join("src")).unwrap();
+
+        assert_eq!(relative_to(&root, &root.join("src/main.rs")).unwrap(), Path::new("src/main.rs"));
+        assert_eq!(relative_to(&root, &root.join("src/../Cargo.toml")).unwrap(), Path::new("Cargo.toml"));
+
+        for file in [root.join("../outside.txt"), root.join("src/../../outside.txt"), root.join(".."), PathBuf::from("/etc/passwd")] {
+            assert!(relative_to(&root, &file).is_err(), "{} was accepted", file.display());
+        }
+    }
 }
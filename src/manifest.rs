@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use regex::Regex;
+use semver::Version;
+use serde::Deserialize;
+use toml_edit::{value, DocumentMut, Item, Table};
+
+use crate::store::PresetName;
+use crate::variables;
+
+/// Name of the manifest file at the root of a preset.
+pub const FILE_NAME: &str = "preset.toml";
+
+/// Metadata describing a preset, read from its `preset.toml`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Manifest {
+    pub description: Option<String>,
+    pub version: Option<Version>,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub variables: BTreeMap<String, VariableSpec>,
+    pub min_cargo_preset_version: Option<Version>,
+    /// Presets whose files this one builds on, overriding files they share.
+    #[serde(default)]
+    pub extends: Vec<String>,
+    /// Where the preset was imported from.
+    pub source: Option<Source>,
+}
+
+/// Git repository a preset was imported from.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Source {
+    pub git: String,
+    /// Revision that was asked for, if any.
+    pub rev: Option<String>,
+    /// Directory of the repository the preset is in.
+    pub subdir: Option<PathBuf>,
+    /// Commit that was imported.
+    pub commit: String,
+}
+
+/// A variable a preset's templates expect.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct VariableSpec {
+    pub description: Option<String>,
+    pub default: Option<toml::Value>,
+    /// The only values allowed, if not empty.
+    #[serde(default)]
+    pub choices: Vec<toml::Value>,
+    /// Regular expression string values have to match as a whole.
+    pub pattern: Option<String>,
+}
+
+impl Manifest {
+    /// Load the manifest of the preset in `dir`, or an empty one if it has none.
+    pub fn load(dir: &Path) -> anyhow::Result<Self> {
+        let path = dir.join(FILE_NAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let manifest: Self = toml::from_str(&contents)
+            .with_context(|| format!("Invalid {}", path.display()))?;
+        manifest.validate()
+            .with_context(|| format!("Invalid {}", path.display()))?;
+        Ok(manifest)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        for (i, tag) in self.tags.iter().enumerate() {
+            if tag.trim().is_empty() {
+                anyhow::bail!("`tags[{i}]`: tags can't be empty");
+            }
+        }
+
+        for (i, parent) in self.extends.iter().enumerate() {
+            if let Err(err) = parent.parse::<PresetName>() {
+                anyhow::bail!(format!("`extends[{i}]`: {err}"));
+            }
+        }
+
+        for (name, spec) in &self.variables {
+            if !is_identifier(name) {
+                anyhow::bail!(
+                    "`variables.{name}`: variable names must start with a letter or `_` \
+                    and contain only letters, digits and `_`"
+                );
+            }
+            if let Some(pattern) = &spec.pattern {
+                if let Err(err) = Regex::new(pattern) {
+                    anyhow::bail!(format!("`variables.{name}.pattern`: {err}"));
+                }
+            }
+            if let Some(default) = &spec.default {
+                if let Err(err) = variables::check(default, spec) {
+                    anyhow::bail!(format!("`variables.{name}.default`: {err}"));
+                }
+            }
+        }
+
+        if let Some(required) = &self.min_cargo_preset_version {
+            let current: Version = env!("CARGO_PKG_VERSION").parse()?;
+            if *required > current {
+                anyhow::bail!(
+                    "`min-cargo-preset-version`: preset requires cargo-preset {required}, \
+                    but this is {current}"
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Record `source` in the manifest of the preset in `dir`, keeping the rest
+/// of the file as it is.
+pub fn set_source(dir: &Path, source: &Source) -> anyhow::Result<()> {
+    let path = dir.join(FILE_NAME);
+    let mut doc = if path.exists() {
+        fs::read_to_string(&path)?.parse::<DocumentMut>()
+            .with_context(|| format!("Invalid {}", path.display()))?
+    } else {
+        DocumentMut::new()
+    };
+
+    let mut table = Table::new();
+    table.insert("git", value(&source.git));
+    if let Some(rev) = &source.rev {
+        table.insert("rev", value(rev));
+    }
+    if let Some(subdir) = &source.subdir {
+        table.insert("subdir", value(subdir.to_string_lossy().as_ref()));
+    }
+    table.insert("commit", value(&source.commit));
+    doc.insert("source", Item::Table(table));
+
+    fs::write(&path, doc.to_string())?;
+    Ok(())
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
@@ -0,0 +1,97 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+/// Nearest directory at or above `start` that has a Cargo.toml.
+pub fn find_root(start: &Path) -> Option<PathBuf> {
+    start.ancestors()
+        .find(|dir| dir.join("Cargo.toml").is_file())
+        .map(Path::to_owned)
+}
+
+/// Command running the cargo that invoked us, or the one on `PATH`.
+pub fn cargo() -> Command {
+    Command::new(std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+}
+
+/// Directory of the manifest at `manifest_path`, checked with `cargo locate-project`.
+pub fn manifest_dir(manifest_path: &Path) -> anyhow::Result<PathBuf> {
+    let mut command = cargo();
+    command.args(["locate-project", "--message-format", "plain", "--manifest-path"])
+        .arg(manifest_path);
+    locate_project(command)
+}
+
+/// Root directory of the workspace the project in `dir` belongs to.
+pub fn workspace_root(dir: &Path) -> anyhow::Result<PathBuf> {
+    let mut command = cargo();
+    command.args(["locate-project", "--workspace", "--message-format", "plain"])
+        .current_dir(dir);
+    locate_project(command)
+}
+
+/// A package of a workspace.
+pub struct Member {
+    pub name: String,
+    /// Directory of the package's Cargo.toml.
+    pub dir: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    packages: Vec<Package>,
+    workspace_members: Vec<String>,
+    workspace_root: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    id: String,
+    name: String,
+    manifest_path: PathBuf,
+}
+
+/// Root of the workspace the project in `dir` belongs to and its members,
+/// read with `cargo metadata`.
+pub fn workspace_members(dir: &Path) -> anyhow::Result<(PathBuf, Vec<Member>)> {
+    let mut command = cargo();
+    command.args(["metadata", "--no-deps", "--format-version", "1"])
+        .current_dir(dir);
+    let metadata: Metadata = serde_json::from_str(&run(command, "metadata")?)
+        .context("Invalid output of cargo metadata")?;
+
+    let members = metadata.packages.into_iter()
+        .filter(|package| metadata.workspace_members.contains(&package.id))
+        .filter_map(|package| Some(Member {
+            dir: package.manifest_path.parent()?.to_owned(),
+            name: package.name,
+        }))
+        .collect();
+    Ok((metadata.workspace_root, members))
+}
+
+/// Run a `cargo locate-project` command and return the directory of the
+/// manifest it found.
+fn locate_project(command: Command) -> anyhow::Result<PathBuf> {
+    let manifest = PathBuf::from(run(command, "locate-project")?.trim());
+    match manifest.parent() {
+        Some(dir) => Ok(dir.to_owned()),
+        None => anyhow::bail!(format!("{} has no parent directory", manifest.display())),
+    }
+}
+
+/// Run the cargo `command` and return its output, failing with cargo's error.
+fn run(mut command: Command, subcommand: &str) -> anyhow::Result<String> {
+    let output = command.output()
+        .map_err(|err| anyhow::anyhow!("Could not run cargo: {err}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match stderr.trim().trim_start_matches("error: ") {
+            "" => anyhow::bail!(format!("cargo {subcommand} failed ({})", output.status)),
+            message => anyhow::bail!(message.to_owned()),
+        }
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
@@ -0,0 +1,412 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::Context;
+use tempfile::TempDir;
+
+use crate::cargo_toml;
+use crate::digest::Hasher;
+use crate::journal;
+use crate::lockfile;
+use crate::manifest::{self, Manifest};
+use crate::project;
+use crate::template;
+
+/// Files in a preset that describe it rather than being part of its output.
+pub const RESERVED: &[&str] = &[manifest::FILE_NAME, cargo_toml::FRAGMENT_FILE_NAME];
+
+/// Whether `rel`, relative to a preset's root, is one of its [`RESERVED`] files,
+/// or state cargo-preset keeps in projects, which is never applied.
+pub fn is_reserved(rel: &Path) -> bool {
+    RESERVED.iter().any(|name| rel == Path::new(name)) || is_project_state(rel)
+}
+
+/// Whether `rel`, relative to a project, is the lockfile or inside the
+/// directory of the journal and base snapshots.
+pub fn is_project_state(rel: &Path) -> bool {
+    rel == Path::new(lockfile::FILE_NAME) || rel.starts_with(journal::STATE_DIR)
+}
+
+/// Names of devices on Windows, which can't be used as directory names there.
+const DEVICE_NAMES: &[&str] = &[
+    "con", "prn", "aux", "nul",
+    "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8", "com9",
+    "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// Name of a preset, checked to be a plain directory name so that it can't
+/// point outside of the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PresetName(String);
+
+impl FromStr for PresetName {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<Self> {
+        if name.trim().is_empty() {
+            anyhow::bail!("Preset names can't be empty");
+        }
+        if name.contains(['/', '\\']) {
+            anyhow::bail!(format!("Preset name {name:?} can't contain path separators"));
+        }
+        if name.starts_with('.') {
+            // Also covers `.` and `..`, and keeps the store's staging directories apart.
+            anyhow::bail!(format!("Preset name {name:?} can't start with `.`"));
+        }
+        if name.chars().any(|c| c.is_control() || matches!(c, ':' | '*' | '?' | '"' | '<' | '>' | '|')) {
+            anyhow::bail!(format!("Preset name {name:?} contains characters that can't be in file names"));
+        }
+        let stem = name.split('.').next().unwrap_or(name).to_ascii_lowercase();
+        if DEVICE_NAMES.contains(&stem.as_str()) {
+            anyhow::bail!(format!("Preset name {name:?} is reserved"));
+        }
+        Ok(Self(name.to_owned()))
+    }
+}
+
+impl Deref for PresetName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for PresetName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<PresetName> for String {
+    fn from(name: PresetName) -> Self {
+        name.0
+    }
+}
+
+/// Store used when none is given: `$XDG_CONFIG_HOME/cargo_preset`, or
+/// `$HOME/.config/cargo_preset` if that isn't set.
+pub fn default_root() -> anyhow::Result<PathBuf> {
+    // The spec says to ignore relative paths.
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute());
+    let config = match config {
+        Some(config) => config,
+        None => match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".config"),
+            None => anyhow::bail!("Home directory not found, pass --store or set CARGO_PRESET_HOME"),
+        },
+    };
+    Ok(config.join("cargo_preset"))
+}
+
+/// Directory at the root of a project holding presets checked into it.
+pub const PROJECT_DIR: &str = ".cargo-presets";
+
+/// Environment variable listing shared, read-only stores, like `PATH`.
+pub const PATH_VAR: &str = "CARGO_PRESET_PATH";
+
+/// Where a preset comes from, in the order stores are searched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// The `.cargo-presets` directory of the current project.
+    Project,
+    /// The user's own store, the only one presets are added to and removed from.
+    User,
+    /// A shared store from `CARGO_PRESET_PATH`.
+    System,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Project => "project",
+            Self::User => "user",
+            Self::System => "system",
+        })
+    }
+}
+
+/// Nearest `.cargo-presets` at or above `project`, up to its workspace root.
+fn find_project_store(project: &Path) -> Option<PathBuf> {
+    let own = project.join(PROJECT_DIR);
+    if own.is_dir() {
+        return Some(own);
+    }
+    let workspace = project::workspace_root(project).ok()?;
+    project.ancestors()
+        .take_while(|dir| dir.starts_with(&workspace))
+        .map(|dir| dir.join(PROJECT_DIR))
+        .find(|dir| dir.is_dir())
+}
+
+/// Directories holding one subdirectory per preset, searched in order: the
+/// project's, the user's, then the system ones. A preset in an earlier one
+/// shadows presets with the same name in later ones.
+pub struct Store {
+    project: Option<PathBuf>,
+    root: PathBuf,
+    system: Vec<PathBuf>,
+}
+
+impl Store {
+    /// Store with `root` as the user's store, searching the `.cargo-presets`
+    /// of `project` before it and the directories in `CARGO_PRESET_PATH` after.
+    ///
+    /// The `.cargo-presets` may also be in a parent of `project`, up to the
+    /// root of its workspace, the nearest one being used.
+    pub fn new(root: PathBuf, project: Option<&Path>) -> Self {
+        let project = project.and_then(find_project_store);
+        let system = std::env::var_os(PATH_VAR)
+            .map(|paths| std::env::split_paths(&paths).filter(|dir| !dir.as_os_str().is_empty()).collect())
+            .unwrap_or_default();
+        Self { project, root, system }
+    }
+
+    /// Every store directory in search order.
+    fn roots(&self) -> impl Iterator<Item = (Origin, &Path)> {
+        self.project.iter().map(|dir| (Origin::Project, dir.as_path()))
+            .chain([(Origin::User, self.root.as_path())])
+            .chain(self.system.iter().map(|dir| (Origin::System, dir.as_path())))
+    }
+
+    /// Names of all presets, sorted.
+    pub fn names(&self) -> anyhow::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for (_, root) in self.roots() {
+            if !root.is_dir() {
+                continue;
+            }
+            names.extend(root.read_dir()?
+                .map(|dir| dir.expect("Could not get directory entry"))
+                // Presets are directories, stray files like a README aren't.
+                .filter(|dir| dir.file_type().is_ok_and(|file_type| file_type.is_dir()))
+                .map(|dir| {
+                    dir.file_name()
+                        .to_str()
+                        .expect("Could not convert filename into string")
+                        .to_owned()
+                })
+                // Also leaves out leftovers of interrupted imports, which are hidden.
+                .filter(|name| name.parse::<PresetName>().is_ok()));
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// Every store that has a preset `name`, the first one being the one used.
+    pub fn origins(&self, name: &str) -> Vec<(Origin, PathBuf)> {
+        self.roots()
+            .map(|(origin, root)| (origin, root.join(name)))
+            .filter(|(_, dir)| dir.is_dir())
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.path(name).is_dir()
+    }
+
+    /// Directory of the preset `name`, in the user's store if no store has it.
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.roots()
+            .map(|(_, root)| root.join(name))
+            .find(|dir| dir.is_dir())
+            .unwrap_or_else(|| self.root.join(name))
+    }
+
+    pub fn manifest(&self, name: &str) -> anyhow::Result<Manifest> {
+        Manifest::load(&self.path(name))
+    }
+
+    /// The preset `name` and every preset it extends, parents first.
+    pub fn layers(&self, name: &str) -> anyhow::Result<Vec<String>> {
+        let mut layers = Vec::new();
+        self.resolve_layers(name, &mut Vec::new(), &mut layers)?;
+        Ok(layers)
+    }
+
+    fn resolve_layers(&self, name: &str, chain: &mut Vec<String>, layers: &mut Vec<String>) -> anyhow::Result<()> {
+        if chain.iter().any(|preset| preset == name) {
+            chain.push(name.to_owned());
+            anyhow::bail!(format!("Preset {name} extends itself: {}", chain.join(" -> ")));
+        }
+        if layers.iter().any(|layer| layer == name) {
+            return Ok(());
+        }
+        if !self.contains(name) {
+            match chain.last() {
+                Some(child) => anyhow::bail!(format!("Preset {child} extends {name}, which doesn't exist")),
+                None => anyhow::bail!(format!("Could not find preset with name {name}")),
+            }
+        }
+
+        chain.push(name.to_owned());
+        for parent in self.manifest(name)?.extends {
+            self.resolve_layers(&parent, chain, layers)?;
+        }
+        chain.pop();
+
+        layers.push(name.to_owned());
+        Ok(())
+    }
+
+    /// Files of the preset `name` and its parents, relative to the preset, with
+    /// the layers that have them. The last layer's file is the one used.
+    pub fn layered_files(&self, name: &str) -> anyhow::Result<BTreeMap<PathBuf, Vec<String>>> {
+        let mut files: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for layer in self.layers(name)? {
+            let root = self.path(&layer);
+            for path in template::walk(&root)? {
+                let rel = path.strip_prefix(&root)?;
+                if !is_reserved(rel) {
+                    files.entry(rel.to_owned()).or_default().push(layer.clone());
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Hash of every file in a preset and the ones it extends, to tell versions of it apart.
+    pub fn content_hash(&self, name: &str) -> anyhow::Result<String> {
+        let mut hasher = Hasher::default();
+        for layer in self.layers(name)? {
+            hasher.update(layer.as_bytes());
+            hasher.update(&[0]);
+            hasher.update(self.dir_hash(&layer)?.as_bytes());
+        }
+        Ok(hasher.finish())
+    }
+
+    fn dir_hash(&self, name: &str) -> anyhow::Result<String> {
+        let root = self.path(name);
+        let mut files = template::walk(&root)?;
+        files.sort();
+
+        let mut hasher = Hasher::default();
+        for path in files {
+            let contents = fs::read(&path)?;
+            hasher.update(path.strip_prefix(&root)?.to_string_lossy().as_bytes());
+            hasher.update(&[0]);
+            hasher.update(&(contents.len() as u64).to_le_bytes());
+            hasher.update(&contents);
+        }
+        Ok(hasher.finish())
+    }
+
+    /// Copy the directory `src` into a staging directory in the user's store,
+    /// leaving out `.git`, to be turned into a preset with [`Store::commit`].
+    pub fn stage(&self, src: &Path) -> anyhow::Result<TempDir> {
+        let staging = tempfile::Builder::new().prefix(".staging-").tempdir_in(&self.root)?;
+        for path in template::walk(src)? {
+            let rel = path.strip_prefix(src)?;
+            if rel.components().any(|component| component.as_os_str() == ".git") {
+                continue;
+            }
+
+            let dest = staging.path().join(rel);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&path, &dest)
+                .with_context(|| format!("Could not copy {}", path.display()))?;
+        }
+        Ok(staging)
+    }
+
+    /// Move a staged directory into place as the preset `name` in the user's
+    /// store, replacing it if it exists.
+    pub fn commit(&self, name: &str, staged: TempDir) -> anyhow::Result<()> {
+        let dest = self.root.join(name);
+        if dest.exists() {
+            self.check_inside(name)?;
+            fs::remove_dir_all(&dest)?;
+        }
+        fs::rename(staged.keep(), dest)?;
+        Ok(())
+    }
+
+    /// Remove a preset of the user's store and everything in it.
+    pub fn remove(&self, name: &str) -> anyhow::Result<()> {
+        if !self.root.join(name).is_dir() {
+            match self.origins(name).first() {
+                Some((origin, dir)) => anyhow::bail!(format!(
+                    "Preset {name} is in the {origin} store at {}, only presets of the user store can be removed",
+                    dir.display()
+                )),
+                None => anyhow::bail!(format!("Could not find preset with name {name}")),
+            }
+        }
+        fs::remove_dir_all(self.check_inside(name)?)?;
+        Ok(())
+    }
+
+    /// Resolve the directory of the existing preset `name` in the user's
+    /// store, making sure it is right inside it, to keep destructive
+    /// operations from reaching outside of it.
+    fn check_inside(&self, name: &str) -> anyhow::Result<PathBuf> {
+        name.parse::<PresetName>()?;
+        let root = self.root.canonicalize()?;
+        let dir = self.root.join(name).canonicalize()?;
+        if dir.parent() != Some(root.as_path()) {
+            anyhow::bail!(format!("Preset {name} resolves to {}, outside of {}", dir.display(), root.display()));
+        }
+        Ok(dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preset_names_reject_paths_and_reserved_names() {
+        for name in ["", " ", ".", "..", ".x", "a/b", "a\\b", "../a", "con", "CON.txt", "nul", "com1", "a:b", "a\u{0}b"] {
+            assert!(name.parse::<PresetName>().is_err(), "{name:?} was accepted");
+        }
+        for name in ["rust", "my-preset", "v1.2", "console", "a.con"] {
+            assert_eq!(&*name.parse::<PresetName>().unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn names_leave_out_files() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("preset")).unwrap();
+        fs::write(root.path().join("README.md"), "").unwrap();
+        let store = Store::new(root.path().to_owned(), None);
+
+        assert_eq!(store.names().unwrap(), ["preset"]);
+    }
+
+    #[test]
+    fn check_inside_accepts_presets_of_the_store() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("preset")).unwrap();
+        let store = Store::new(root.path().to_owned(), None);
+
+        let dir = store.check_inside("preset").unwrap();
+        assert_eq!(dir, root.path().canonicalize().unwrap().join("preset"));
+        assert!(store.check_inside("..").is_err());
+        assert!(store.check_inside("missing").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_inside_rejects_symlinks_out_of_the_store() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("keep.txt"), "keep").unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("evil")).unwrap();
+        let store = Store::new(root.path().to_owned(), None);
+
+        assert!(store.check_inside("evil").is_err());
+        assert!(store.remove("evil").is_err());
+        assert!(outside.path().join("keep.txt").exists());
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::git;
+use crate::manifest::{self, Manifest, Source};
+use crate::store::{Origin, Store};
+use crate::template;
+
+/// Refresh the preset `name` from the repository it was imported from and
+/// print the files that changed.
+///
+/// `pin` replaces the recorded revision: `Some(None)` follows the default
+/// branch again, `None` keeps whatever was recorded.
+pub fn sync(store: &Store, name: &str, pin: Option<Option<String>>, dry_run: bool) -> anyhow::Result<()> {
+    if let Some((origin, dir)) = store.origins(name).first() {
+        if *origin != Origin::User {
+            anyhow::bail!(format!(
+                "Preset {name} is in the {origin} store at {}, only presets of the user store can be synced",
+                dir.display()
+            ));
+        }
+    }
+    let Some(source) = store.manifest(name)?.source else {
+        anyhow::bail!(format!("Preset {name} wasn't imported from a git repository"));
+    };
+    let rev = pin.unwrap_or(source.rev.clone());
+
+    let checkout = git::checkout(&source.git, rev.as_deref())?;
+    if checkout.commit == source.commit && rev == source.rev {
+        println!("{name} is up to date at {}", short(&source.commit));
+        return Ok(());
+    }
+
+    let src = match &source.subdir {
+        Some(subdir) => checkout.path().join(subdir),
+        None => checkout.path().to_owned(),
+    };
+    if !src.is_dir() {
+        anyhow::bail!(format!(
+            "{} no longer has directory {}",
+            source.git,
+            source.subdir.unwrap_or_default().display()
+        ));
+    }
+
+    let staged = store.stage(&src)?;
+    let new_source = Source { rev, commit: checkout.commit, ..source };
+    manifest::set_source(staged.path(), &new_source)?;
+    Manifest::load(staged.path())?;
+
+    match &new_source.rev {
+        Some(rev) => println!("{name}: {} -> {} ({rev})", short(&source.commit), short(&new_source.commit)),
+        None => println!("{name}: {} -> {}", short(&source.commit), short(&new_source.commit)),
+    }
+    for (change, path) in changes(&store.path(name), staged.path())? {
+        println!("\t{change:<8} {}", path.display());
+    }
+
+    if !dry_run {
+        store.commit(name, staged)?;
+    }
+    Ok(())
+}
+
+/// Files that were added, removed or modified going from `old` to `new`.
+fn changes(old: &Path, new: &Path) -> anyhow::Result<Vec<(&'static str, PathBuf)>> {
+    let files = |dir: &Path| -> anyhow::Result<BTreeSet<PathBuf>> {
+        template::walk(dir)?.into_iter()
+            .map(|path| Ok(path.strip_prefix(dir)?.to_owned()))
+            .collect()
+    };
+    let old_files = files(old)?;
+    let new_files = files(new)?;
+
+    let mut changes = Vec::new();
+    for path in old_files.union(&new_files) {
+        let change = match (old_files.contains(path), new_files.contains(path)) {
+            (false, _) => "added",
+            (_, false) => "removed",
+            _ if fs::read(old.join(path))? != fs::read(new.join(path))? => "modified",
+            _ => continue,
+        };
+        changes.push((change, path.clone()));
+    }
+    Ok(changes)
+}
+
+fn short(commit: &str) -> &str {
+    commit.get(..7).unwrap_or(commit)
+}
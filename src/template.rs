@@ -0,0 +1,95 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use heck::{ToKebabCase, ToPascalCase, ToSnakeCase};
+use minijinja::{Environment, UndefinedBehavior};
+
+use crate::store;
+use crate::variables::Variables;
+
+/// Files ending with this suffix are rendered as templates and written without it.
+pub const TEMPLATE_SUFFIX: &str = ".tmpl";
+
+/// A file produced from a preset, relative to the directory it is applied into.
+pub struct RenderedFile {
+    pub path: PathBuf,
+    pub contents: Vec<u8>,
+}
+
+/// Render every file of the preset at `root`, in path order.
+pub fn render_dir(root: &Path, vars: &Variables) -> anyhow::Result<Vec<RenderedFile>> {
+    let env = environment();
+    let ctx = minijinja::Value::from_serialize(vars);
+
+    let mut files = Vec::new();
+    for path in walk(root)? {
+        let rel = path.strip_prefix(root)?;
+        if store::is_reserved(rel) {
+            continue;
+        }
+        let contents = fs::read(&path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+
+        let file = match template_target(rel) {
+            Some(target) => {
+                let source = String::from_utf8(contents)
+                    .with_context(|| format!("Template {} is not valid UTF-8", rel.display()))?;
+                let name = rel.to_string_lossy();
+                let rendered = env.render_named_str(&name, &source, &ctx)
+                    .with_context(|| format!("Could not render {name}"))?;
+                RenderedFile { path: target, contents: rendered.into_bytes() }
+            }
+            None => RenderedFile { path: rel.to_owned(), contents },
+        };
+        // Templates could also render to the project's state.
+        if store::is_project_state(&file.path) {
+            continue;
+        }
+        files.push(file);
+    }
+
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    // Undefined variables are an error when printed or iterated, but may
+    // still be tested with `{% if %}`.
+    env.set_undefined_behavior(UndefinedBehavior::SemiStrict);
+    env.set_keep_trailing_newline(true);
+    // Case conversions, to turn names like `crate_name` into module and type names.
+    env.add_filter("snake_case", |value: String| value.to_snake_case());
+    env.add_filter("kebab_case", |value: String| value.to_kebab_case());
+    env.add_filter("pascal_case", |value: String| value.to_pascal_case());
+    env
+}
+
+/// Path a template is written to, or `None` if `path` isn't a template.
+fn template_target(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let stem = name.strip_suffix(TEMPLATE_SUFFIX)?;
+    if stem.is_empty() {
+        return None;
+    }
+    Some(path.with_file_name(stem))
+}
+
+/// All files below `dir`, recursively. Symlinks are skipped, as they could
+/// pull files from outside of `dir` into a preset.
+pub fn walk(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            eprintln!("Skipping symlink {}", entry.path().display());
+        } else if file_type.is_dir() {
+            files.extend(walk(&entry.path())?);
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
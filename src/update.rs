@@ -0,0 +1,139 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use crate::apply::{self, Plan, Planned, Status};
+use crate::cargo_toml::VersionConflict;
+use crate::digest;
+use crate::lockfile::{self, LockedPreset, Lockfile};
+use crate::store::Store;
+use crate::template::RenderedFile;
+use crate::variables::{self, Variables};
+
+/// Plan updating the presets `names` applied to `dir` to their contents in the store.
+///
+/// Files the project didn't change since the last apply are replaced. Files
+/// changed on both sides are merged against the contents the preset rendered
+/// back then, with conflict markers where the changes overlap.
+///
+/// Presets are rendered with the variables they were applied with, overridden
+/// by `defined` ones. Values of new variables are asked for if `interactive`
+/// and added to `defined`, as in [`apply::plan_presets`].
+pub fn plan_update(
+    store: &Store,
+    dir: &Path,
+    names: &[String],
+    defined: &mut Variables,
+    interactive: bool,
+    version_conflict: VersionConflict,
+) -> anyhow::Result<Plan> {
+    let lockfile = Lockfile::load(dir)?;
+    let mut plan = Plan::new(dir.to_owned());
+
+    for name in names {
+        let Some(locked) = lockfile.get(name) else {
+            anyhow::bail!(format!("Preset {name} hasn't been applied to {}", dir.display()));
+        };
+        if !store.contains(name) {
+            anyhow::bail!(format!("Could not find preset with name {name}"));
+        }
+        if store.content_hash(name)? == locked.hash {
+            println!("{name} is up to date");
+            continue;
+        }
+
+        let mut vars = locked.variables.clone();
+        vars.extend(defined.clone());
+        for (var, value) in variables::builtin(dir)? {
+            vars.entry(var).or_insert(value);
+        }
+        let rendered = apply::render_preset(store, name, dir, vars, interactive, version_conflict, plan.cargo_manifest())?;
+        defined.extend(rendered.answered);
+
+        let mut paths = BTreeSet::new();
+        for file in rendered.files {
+            paths.insert(file.path.to_string_lossy().into_owned());
+            plan_file(&mut plan, locked, file)?;
+        }
+
+        for (path, hash) in &locked.files {
+            if paths.contains(path) {
+                continue;
+            }
+
+            let base = lockfile::base_path(name, Path::new(path));
+            if dir.join(&base).exists() {
+                let removed = RenderedFile { path: base, contents: Vec::new() };
+                plan.push(Planned::new(removed, None, Status::Remove));
+            }
+            match fs::read(dir.join(path)) {
+                Ok(contents) if digest::sha256(&contents) == *hash => {
+                    let removed = RenderedFile { path: path.into(), contents: Vec::new() };
+                    plan.push(Planned::new(removed, None, Status::Remove));
+                }
+                Ok(_) => println!("{name} no longer has {path}, keeping it since it was changed"),
+                Err(_) => {}
+            }
+        }
+
+        if let Some(cargo_manifest) = rendered.cargo_manifest {
+            plan.add(cargo_manifest, None)?;
+        }
+        plan.presets.push(rendered.locked);
+    }
+
+    Ok(plan)
+}
+
+fn plan_file(plan: &mut Plan, locked: &LockedPreset, file: RenderedFile) -> anyhow::Result<()> {
+    let name = &locked.name;
+    let key = file.path.to_string_lossy().into_owned();
+    let dest = plan.dir.join(&file.path);
+
+    if !dest.is_file() {
+        if !dest.exists() && locked.files.contains_key(&key) {
+            println!("{} was deleted since {name} was applied, not restoring it", file.path.display());
+            return Ok(());
+        }
+        return plan.add(file, Some(name));
+    }
+
+    let ours = fs::read(&dest)?;
+    if ours == file.contents {
+        plan.push(Planned::new(file, Some(name), Status::Identical));
+        return Ok(());
+    }
+
+    let Some(hash) = locked.files.get(&key) else {
+        // Not written by the preset before, so it's an ordinary conflict.
+        return plan.add(file, Some(name));
+    };
+    if digest::sha256(&ours) == *hash {
+        plan.push(Planned::new(file, Some(name), Status::Overwrite));
+        return Ok(());
+    }
+
+    let base = fs::read(plan.dir.join(lockfile::base_path(name, &file.path))).unwrap_or_default();
+    let (Ok(base), Ok(ours), Ok(theirs)) = (
+        String::from_utf8(base),
+        String::from_utf8(ours),
+        std::str::from_utf8(&file.contents),
+    ) else {
+        // Binary files can't be merged, leave them to the conflict policy.
+        plan.push(Planned::new(file, Some(name), Status::Conflict));
+        return Ok(());
+    };
+
+    let (merged, status) = match diffy::merge(&base, &ours, theirs) {
+        Ok(merged) => (merged, Status::Merged),
+        Err(merged) => (merged, Status::Conflicted),
+    };
+    let mut planned = Planned::new(
+        RenderedFile { path: file.path, contents: merged.into_bytes() },
+        Some(name),
+        status,
+    );
+    planned.rendered = Some(file.contents);
+    plan.push(planned);
+    Ok(())
+}
@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use regex::Regex;
+use toml::Value;
+
+use crate::manifest::VariableSpec;
+
+/// Values available to templates while applying a preset.
+pub type Variables = BTreeMap<String, Value>;
+
+/// Fields of `[package]` available as variables of the same name.
+const PACKAGE_FIELDS: &[&str] = &["version", "edition", "authors", "license", "repository"];
+
+/// Variables derived from the project in `dir` and the environment.
+pub fn builtin(dir: &Path) -> anyhow::Result<Variables> {
+    let mut vars = Variables::new();
+    let package = package(dir)?.unwrap_or_default();
+
+    let crate_name = package.get("name")
+        .and_then(Value::as_str)
+        .or_else(|| dir.file_name().and_then(|name| name.to_str()));
+    if let Some(name) = crate_name {
+        vars.insert("crate_name".into(), Value::String(name.to_owned()));
+    }
+
+    for field in PACKAGE_FIELDS {
+        // Fields still inherited from a workspace that couldn't be found are left out.
+        if let Some(value @ (Value::String(_) | Value::Array(_))) = package.get(*field) {
+            vars.insert((*field).into(), value.clone());
+        }
+    }
+    if !vars.contains_key("repository") {
+        if let Some(url) = git_config(dir, "remote.origin.url") {
+            vars.insert("repository".into(), Value::String(url));
+        }
+    }
+
+    if let Some(author) = git_config(dir, "user.name") {
+        vars.insert("author".into(), Value::String(author));
+    }
+    if let Some(email) = git_config(dir, "user.email") {
+        vars.insert("author_email".into(), Value::String(email));
+    }
+
+    vars.insert("year".into(), Value::Integer(current_year()));
+    Ok(vars)
+}
+
+/// Give every variable declared in `specs` a value: the one it has in
+/// `vars`, an answer to a prompt offering its default if `interactive`, or
+/// its default otherwise.
+///
+/// Fails if a value isn't allowed, or if variables are left without one.
+pub fn resolve(vars: &mut Variables, specs: &BTreeMap<String, VariableSpec>, interactive: bool) -> anyhow::Result<()> {
+    let mut missing = Vec::new();
+    for (name, spec) in specs {
+        let value = match vars.get(name) {
+            Some(value) => {
+                let value = coerce(value.clone(), spec)
+                    .and_then(|value| check(&value, spec).map(|()| value))
+                    .with_context(|| format!("Invalid value for variable {name}"))?;
+                Some(value)
+            }
+            None if interactive => Some(ask(name, spec)?),
+            None => spec.default.clone(),
+        };
+        match value {
+            Some(value) => {
+                vars.insert(name.clone(), value);
+            }
+            None => missing.push(name.as_str()),
+        }
+    }
+
+    if !missing.is_empty() {
+        anyhow::bail!(format!(
+            "No value for variables {}, pass them with --define or --values",
+            missing.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Check that `value` is one of the variable's choices and matches its pattern.
+pub fn check(value: &Value, spec: &VariableSpec) -> anyhow::Result<()> {
+    if !spec.choices.is_empty() && !spec.choices.contains(value) {
+        let choices: Vec<_> = spec.choices.iter().map(show).collect();
+        anyhow::bail!(format!("{} is not one of {}", show(value), choices.join(", ")));
+    }
+    if let Some(pattern) = &spec.pattern {
+        let regex = Regex::new(&format!("^(?:{pattern})$"))?;
+        match value.as_str() {
+            Some(value) if regex.is_match(value) => {}
+            _ => anyhow::bail!(format!("{} doesn't match {pattern}", show(value))),
+        }
+    }
+    Ok(())
+}
+
+/// Convert strings, as given on the command line or in a prompt, to the type
+/// of the variable's default or choices.
+fn coerce(value: Value, spec: &VariableSpec) -> anyhow::Result<Value> {
+    let Value::String(string) = &value else {
+        return Ok(value);
+    };
+    let Some(example) = spec.default.as_ref().or(spec.choices.first()) else {
+        return Ok(value);
+    };
+
+    Ok(match example {
+        Value::Integer(_) => Value::Integer(string.trim().parse()
+            .with_context(|| format!("{string:?} is not an integer"))?),
+        Value::Float(_) => Value::Float(string.trim().parse()
+            .with_context(|| format!("{string:?} is not a number"))?),
+        Value::Boolean(_) => match string.trim() {
+            "true" | "yes" | "y" => Value::Boolean(true),
+            "false" | "no" | "n" => Value::Boolean(false),
+            _ => anyhow::bail!(format!("{string:?} is not true or false")),
+        },
+        _ => value,
+    })
+}
+
+fn ask(name: &str, spec: &VariableSpec) -> anyhow::Result<Value> {
+    let stdin = io::stdin();
+    loop {
+        print!("{}", spec.description.as_deref().unwrap_or(name));
+        if !spec.choices.is_empty() {
+            let choices: Vec<_> = spec.choices.iter().map(show).collect();
+            print!(" ({})", choices.join(", "));
+        }
+        if let Some(default) = &spec.default {
+            print!(" [{}]", show(default));
+        }
+        print!(": ");
+        io::stdout().flush()?;
+
+        let mut answer = String::new();
+        if stdin.lock().read_line(&mut answer)? == 0 {
+            anyhow::bail!("Aborted");
+        }
+        let answer = answer.trim_end_matches(['\n', '\r']);
+        if answer.is_empty() {
+            match &spec.default {
+                Some(default) => return Ok(default.clone()),
+                None => {
+                    println!("{name} needs a value");
+                    continue;
+                }
+            }
+        }
+
+        match coerce(Value::String(answer.to_owned()), spec).and_then(|value| check(&value, spec).map(|()| value)) {
+            Ok(value) => return Ok(value),
+            Err(err) => println!("{err}"),
+        }
+    }
+}
+
+/// `value` as typed by a user, without quotes around strings.
+fn show(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        _ => value.to_string(),
+    }
+}
+
+/// `[package]` of the Cargo.toml in `dir`, if there is one, with the fields
+/// it inherits with `workspace = true` filled in from the workspace.
+fn package(dir: &Path) -> anyhow::Result<Option<toml::Table>> {
+    let Some(mut package) = manifest(dir)?.and_then(|manifest| match manifest.get("package") {
+        Some(Value::Table(package)) => Some(package.clone()),
+        _ => None,
+    }) else {
+        return Ok(None);
+    };
+
+    let inherited: Vec<_> = package.iter()
+        .filter(|(_, value)| value.get("workspace").and_then(Value::as_bool) == Some(true))
+        .map(|(key, _)| key.clone())
+        .collect();
+    if inherited.is_empty() {
+        return Ok(Some(package));
+    }
+
+    for ancestor in dir.ancestors() {
+        let shared = manifest(ancestor)?.and_then(|manifest| {
+            manifest.get("workspace")?.get("package").cloned()
+        });
+        if let Some(shared) = shared {
+            for key in inherited {
+                if let Some(value) = shared.get(&key) {
+                    package.insert(key, value.clone());
+                }
+            }
+            break;
+        }
+    }
+    Ok(Some(package))
+}
+
+/// The Cargo.toml in `dir`, if there is one.
+fn manifest(dir: &Path) -> anyhow::Result<Option<toml::Table>> {
+    let path = dir.join("Cargo.toml");
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let manifest = fs::read_to_string(&path)?.parse()
+        .with_context(|| format!("Invalid {}", path.display()))?;
+    Ok(Some(manifest))
+}
+
+/// Value of `key` in the git configuration that applies to `dir`.
+fn git_config(dir: &Path, key: &str) -> Option<String> {
+    let mut command = std::process::Command::new("git");
+    if dir.is_dir() {
+        command.arg("-C").arg(dir);
+    }
+    let output = command.args(["config", "--get", key])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let value = String::from_utf8(output.stdout).ok()?;
+    Some(value.trim().to_owned())
+}
+
+fn current_year() -> i64 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let days = (secs / 86_400) as i64;
+
+    // Civil-from-days conversion, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+
+    yoe + era * 400 + i64::from(month <= 2)
+}
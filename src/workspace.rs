@@ -0,0 +1,192 @@
+use std::fs;
+use std::path::{Component, Path};
+
+use anyhow::Context;
+use globset::GlobBuilder;
+use toml_edit::{Array, DocumentMut, InlineTable, Item, Table};
+
+use crate::apply::Plan;
+use crate::cargo_toml;
+
+/// Check that `member` can be created as a new member of the workspace at `root`.
+pub fn check_new_member(root: &Path, member: &Path) -> anyhow::Result<()> {
+    if member.as_os_str().is_empty()
+        || !member.components().all(|component| matches!(component, Component::Normal(_)))
+    {
+        anyhow::bail!(format!("{} must be a relative path inside the workspace", member.display()));
+    }
+    if root.join(member).exists() {
+        anyhow::bail!(format!("{} already exists", root.join(member).display()));
+    }
+
+    let doc = read_manifest(root)?;
+    if doc.get("workspace").and_then(Item::as_table_like).is_none() {
+        anyhow::bail!(format!("{} is not a workspace, it has no [workspace] table", root.display()));
+    }
+    Ok(())
+}
+
+/// Write a minimal library package named `name` in `dir`, for presets that
+/// don't come with a manifest, using the edition of the workspace at `root`
+/// if it sets one.
+pub fn write_skeleton(dir: &Path, name: &str, root: &Path) -> anyhow::Result<()> {
+    let shared_edition = read_manifest(root)?
+        .get("workspace")
+        .and_then(|workspace| workspace.get("package"))
+        .and_then(|package| package.get("edition"))
+        .is_some();
+
+    let mut package = Table::new();
+    package.insert("name", toml_edit::value(name));
+    package.insert("version", toml_edit::value("0.1.0"));
+    if shared_edition {
+        let mut edition = InlineTable::new();
+        edition.insert("workspace", true.into());
+        package.insert("edition", toml_edit::value(edition));
+    } else {
+        package.insert("edition", toml_edit::value("2021"));
+    }
+
+    let mut doc = DocumentMut::new();
+    doc.insert("package", Item::Table(package));
+    fs::create_dir_all(dir.join("src"))?;
+    fs::write(dir.join("Cargo.toml"), doc.to_string())?;
+    fs::write(dir.join("src").join("lib.rs"), "")?;
+    Ok(())
+}
+
+/// Make the planned Cargo.toml of a new member inherit the dependencies the
+/// workspace at `root` declares.
+pub fn inherit_dependencies(plan: &mut Plan, root: &Path) -> anyhow::Result<()> {
+    let root_manifest = fs::read_to_string(root.join("Cargo.toml"))?;
+    let Some(file) = plan.files.iter_mut().find(|file| file.path == Path::new("Cargo.toml")) else {
+        return Ok(());
+    };
+
+    let inherited = cargo_toml::inherit_workspace_dependencies(&String::from_utf8_lossy(&file.contents), &root_manifest)?;
+    for note in inherited.notes {
+        println!("Cargo.toml: {note}");
+    }
+    file.contents = inherited.contents.into_bytes();
+    Ok(())
+}
+
+/// Cargo.toml of the workspace at `root` with `member` added to
+/// `workspace.members`, or `None` if one of them already covers it.
+///
+/// Fails if a directory that has to be created for the member would itself
+/// be matched by one of the members' patterns, as cargo would then look for
+/// a Cargo.toml in it.
+pub fn with_member(root: &Path, member: &Path) -> anyhow::Result<Option<String>> {
+    let mut doc = read_manifest(root)?;
+    let member = member.to_string_lossy().replace('\\', "/");
+
+    let workspace = doc.entry("workspace")
+        .or_insert_with(toml_edit::table)
+        .as_table_like_mut()
+        .context("`workspace` in the workspace's Cargo.toml is not a table")?;
+    let exclude: Vec<String> = workspace.get("exclude")
+        .and_then(Item::as_array)
+        .map(|exclude| exclude.iter().filter_map(|path| path.as_str()).map(str::to_owned).collect())
+        .unwrap_or_default();
+    let members = workspace.entry("members")
+        .or_insert_with(|| toml_edit::value(Array::new()))
+        .as_array_mut()
+        .context("`workspace.members` in the workspace's Cargo.toml is not an array")?;
+    let patterns: Vec<String> = members.iter().filter_map(|pattern| pattern.as_str()).map(str::to_owned).collect();
+
+    for parent in Path::new(&member).ancestors().skip(1) {
+        if parent.as_os_str().is_empty() || root.join(parent).join("Cargo.toml").exists() {
+            continue;
+        }
+        if let Some(pattern) = covering_pattern(&patterns, &exclude, parent) {
+            anyhow::bail!(format!(
+                "{} would be a workspace member through {pattern:?} without a Cargo.toml, add it to `workspace.exclude` first",
+                parent.display()
+            ));
+        }
+    }
+
+    if covering_pattern(&patterns, &exclude, Path::new(&member)).is_some() {
+        return Ok(None);
+    }
+    members.push(member);
+    Ok(Some(doc.to_string()))
+}
+
+/// Pattern of `members` that makes `path` a workspace member, if any.
+/// Excluded paths are only members when listed as they are.
+fn covering_pattern<'a>(members: &'a [String], exclude: &[String], path: &Path) -> Option<&'a str> {
+    let excluded = exclude.iter().any(|excluded| path.starts_with(excluded.trim_end_matches('/')));
+    members.iter().map(|pattern| pattern.trim_end_matches('/')).find(|pattern| {
+        if Path::new(pattern) == path {
+            return true;
+        }
+        // Like cargo's, member globs don't match across `/`.
+        let matched = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .is_ok_and(|glob| glob.compile_matcher().is_match(path));
+        matched && !excluded
+    })
+}
+
+fn read_manifest(root: &Path) -> anyhow::Result<DocumentMut> {
+    let path = root.join("Cargo.toml");
+    fs::read_to_string(&path)
+        .with_context(|| format!("Could not read {}", path.display()))?
+        .parse()
+        .with_context(|| format!("Invalid {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workspace(manifest: &str) -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("Cargo.toml"), manifest).unwrap();
+        root
+    }
+
+    #[test]
+    fn check_new_member_rejects_paths_outside_of_the_workspace() {
+        let root = workspace("[workspace]\nmembers = []\n");
+        for member in ["..", "../other", "crates/../../other", "/tmp/other", "./crates/one", ""] {
+            assert!(check_new_member(root.path(), Path::new(member)).is_err(), "{member:?} was accepted");
+        }
+        check_new_member(root.path(), Path::new("crates/one")).unwrap();
+
+        fs::create_dir(root.path().join("taken")).unwrap();
+        assert!(check_new_member(root.path(), Path::new("taken")).is_err());
+    }
+
+    #[test]
+    fn with_member_skips_members_covered_by_a_pattern() {
+        let root = workspace("[workspace]\nmembers = [\"crates/*\", \"tools/cli\"]\n");
+        assert!(with_member(root.path(), Path::new("crates/one")).unwrap().is_none());
+        assert!(with_member(root.path(), Path::new("tools/cli")).unwrap().is_none());
+
+        let added = with_member(root.path(), Path::new("tools/other")).unwrap().unwrap();
+        assert!(added.contains("\"tools/other\""), "{added}");
+    }
+
+    #[test]
+    fn with_member_globs_dont_match_across_separators() {
+        let root = workspace("[workspace]\nmembers = [\"crates/*\"]\n");
+        // `crates/sub` would have to be created without a Cargo.toml.
+        assert!(with_member(root.path(), Path::new("crates/sub/deep")).is_err());
+
+        fs::create_dir_all(root.path().join("crates/sub")).unwrap();
+        fs::write(root.path().join("crates/sub/Cargo.toml"), "").unwrap();
+        let added = with_member(root.path(), Path::new("crates/sub/deep")).unwrap().unwrap();
+        assert!(added.contains("\"crates/sub/deep\""), "{added}");
+    }
+
+    #[test]
+    fn with_member_lists_excluded_members() {
+        let root = workspace("[workspace]\nmembers = [\"crates/*\"]\nexclude = [\"crates/skip\"]\n");
+        let added = with_member(root.path(), Path::new("crates/skip")).unwrap().unwrap();
+        assert!(added.contains("\"crates/skip\""), "{added}");
+    }
+}